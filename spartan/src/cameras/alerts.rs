@@ -0,0 +1,398 @@
+use std::env;
+
+use log::debug;
+use mongodb::bson::{doc, DateTime};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::cameras::Camera;
+
+const COLLECTION: &str = "camera_alerts";
+
+/// Alert kinds, notifications reported by the camera are prefixed with NOTIFICATION.
+pub const LOW_BATTERY: &str = "low_battery";
+pub const BATTERY_EMPTY_SOON: &str = "battery_empty_soon";
+pub const SD_CARD_FULL: &str = "sd_card_full";
+pub const WEAK_SIGNAL: &str = "weak_signal";
+pub const STALE_TRANSMISSION: &str = "stale_transmission";
+pub const PHOTO_QUOTA: &str = "photo_quota";
+pub const BILLING_ENDING: &str = "billing_ending";
+pub const NOTIFICATION: &str = "notification";
+
+/// Thresholds at which a camera alert is raised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds {
+    /// Battery percentage at or below which the battery is low.
+    pub battery_level: i64,
+    /// Days before the estimated empty date at which the batteries need replacing.
+    pub battery_days: i64,
+    /// Percentage of the SD card used at or above which the card is full.
+    pub memory_percent: f64,
+    /// Signal bars at or below which the signal is weak.
+    pub signal: i64,
+    /// Hours without a transmission after which the camera is stale.
+    pub stale_hours: i64,
+    /// Percentage of the monthly photo quota used at or above which the quota is nearly used.
+    pub quota_percent: f64,
+    /// Days before the end of a billing cycle without auto-renew at which to warn.
+    pub billing_days: i64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            battery_level: 20,
+            battery_days: 14,
+            memory_percent: 90.0,
+            signal: 1,
+            stale_hours: 36,
+            quota_percent: 90.0,
+            billing_days: 7,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Loads the thresholds from ALERT_BATTERY, ALERT_BATTERY_DAYS, ALERT_MEMORY, ALERT_SIGNAL,
+    /// ALERT_STALE_HOURS, ALERT_QUOTA and ALERT_BILLING_DAYS, missing or invalid vars keep the
+    /// default.
+    pub fn from_env() -> Self {
+        let mut t = Thresholds::default();
+
+        if let Some(x) = env::var("ALERT_BATTERY").ok().and_then(|x| x.parse().ok()) {
+            t.battery_level = x;
+        }
+        if let Some(x) = env::var("ALERT_BATTERY_DAYS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+        {
+            t.battery_days = x;
+        }
+        if let Some(x) = env::var("ALERT_MEMORY").ok().and_then(|x| x.parse().ok()) {
+            t.memory_percent = x;
+        }
+        if let Some(x) = env::var("ALERT_SIGNAL").ok().and_then(|x| x.parse().ok()) {
+            t.signal = x;
+        }
+        if let Some(x) = env::var("ALERT_STALE_HOURS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+        {
+            t.stale_hours = x;
+        }
+        if let Some(x) = env::var("ALERT_QUOTA").ok().and_then(|x| x.parse().ok()) {
+            t.quota_percent = x;
+        }
+        if let Some(x) = env::var("ALERT_BILLING_DAYS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+        {
+            t.billing_days = x;
+        }
+
+        t
+    }
+}
+
+/// An active health alert of a camera. An alert is stored while its condition holds, so it's
+/// only notified once.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub camera_id: String,
+    pub camera_name: String,
+    pub kind: String,
+    pub message: String,
+    pub raised: DateTime,
+}
+
+/// The alerts raised and resolved by a camera status update.
+#[derive(Debug, Clone, Default)]
+pub struct AlertChanges {
+    pub raised: Vec<Alert>,
+    pub resolved: Vec<Alert>,
+}
+
+/// Returns the alerts of the camera's current status.
+///
+/// Arguments:
+///
+/// camera: The camera to check.
+/// thresholds: The alert thresholds.
+/// now: The time of the check.
+pub fn evaluate(camera: &Camera, thresholds: &Thresholds, now: DateTime) -> Vec<Alert> {
+    let status = &camera.status;
+    let mut alerts = Vec::new();
+
+    let mut alert = |kind: String, message: String| {
+        alerts.push(Alert {
+            camera_id: camera.camera_id.clone(),
+            camera_name: camera.name.clone(),
+            kind,
+            message,
+            raised: now,
+        })
+    };
+
+    // Estimated empty date, e.g. "2024-08-01".
+    let empty_date = camera
+        .battery_empty_date
+        .map(|d| d.to_chrono().format("%Y-%m-%d").to_string());
+
+    if status.battery_level <= thresholds.battery_level {
+        let mut message = format!("battery at {}%", status.battery_level);
+        if let Some(d) = &empty_date {
+            message = format!("{}, empty around {}", message, d);
+        }
+        alert(LOW_BATTERY.to_string(), message);
+    }
+
+    if let (Some(date), Some(d)) = (camera.battery_empty_date, &empty_date) {
+        let days = (date.timestamp_millis() - now.timestamp_millis()) / 86_400_000;
+        if days <= thresholds.battery_days {
+            alert(
+                BATTERY_EMPTY_SOON.to_string(),
+                format!(
+                    "batteries empty in about {} days, around {}",
+                    days.max(0),
+                    d
+                ),
+            );
+        }
+    }
+
+    if status.memory_limit > 0.0 {
+        let used = status.memory / status.memory_limit * 100.0;
+        if used >= thresholds.memory_percent {
+            alert(
+                SD_CARD_FULL.to_string(),
+                format!(
+                    "sd card {:.0}% full ({:.0} of {:.0} MB)",
+                    used, status.memory, status.memory_limit
+                ),
+            );
+        }
+    }
+
+    if status.signal <= thresholds.signal {
+        alert(
+            WEAK_SIGNAL.to_string(),
+            format!("signal at {} bar(s)", status.signal),
+        );
+    }
+
+    let hours = (now.timestamp_millis() - status.last_transmission.timestamp_millis()) / 3_600_000;
+    if hours >= thresholds.stale_hours {
+        alert(
+            STALE_TRANSMISSION.to_string(),
+            format!(
+                "no transmission for {} hours, last at {}",
+                hours,
+                status
+                    .last_transmission
+                    .try_to_rfc3339_string()
+                    .unwrap_or_default()
+            ),
+        );
+    }
+
+    if let Some(sub) = &camera.subscription {
+        if let Some(used) = sub.quota_used() {
+            if used >= thresholds.quota_percent {
+                alert(
+                    PHOTO_QUOTA.to_string(),
+                    format!(
+                        "{} of {} monthly photos used on the {} plan",
+                        sub.photo_count, sub.photo_count_per_month, sub.plan_name
+                    ),
+                );
+            }
+        }
+
+        if let Some(days) = sub.days_left(now) {
+            if !sub.is_auto_renew && days <= thresholds.billing_days {
+                alert(
+                    BILLING_ENDING.to_string(),
+                    format!(
+                        "{} plan ends in {} days without auto-renew",
+                        sub.plan_name,
+                        days.max(0)
+                    ),
+                );
+            }
+        }
+    }
+
+    for n in &status.notifications {
+        alert(
+            format!("{}:{}", NOTIFICATION, n),
+            format!("camera notification {}", n),
+        );
+    }
+
+    alerts
+}
+
+/// Compares the alerts of the camera's current status with the stored alerts. New alerts are
+/// stored and alerts whose condition cleared are removed.
+///
+/// Arguments:
+///
+/// db: MongoDB Database
+/// camera: The camera to check.
+/// thresholds: The alert thresholds.
+pub async fn update(
+    db: &Database,
+    camera: &Camera,
+    thresholds: &Thresholds,
+) -> crate::Result<AlertChanges> {
+    let coll: Collection<Alert> = db.collection(COLLECTION);
+
+    let mut cursor = coll.find(doc! { "camera_id": &camera.camera_id }).await?;
+    let mut active = Vec::new();
+    while cursor.advance().await? {
+        active.push(cursor.deserialize_current()?);
+    }
+
+    let current = evaluate(camera, thresholds, DateTime::now());
+    let changes = diff(active, current);
+
+    for a in &changes.raised {
+        debug!("alerts::update raised {} for {}", a.kind, a.camera_name);
+        coll.insert_one(a).await?;
+    }
+
+    for a in &changes.resolved {
+        debug!("alerts::update resolved {} for {}", a.kind, a.camera_name);
+        coll.delete_one(doc! { "camera_id": &a.camera_id, "kind": &a.kind })
+            .await?;
+    }
+
+    Ok(changes)
+}
+
+/// Returns the current alerts that aren't active yet, and the active alerts that are no longer
+/// current.
+pub fn diff(active: Vec<Alert>, current: Vec<Alert>) -> AlertChanges {
+    let raised = current
+        .iter()
+        .filter(|c| !active.iter().any(|a| a.kind == c.kind))
+        .cloned()
+        .collect();
+
+    let resolved = active
+        .into_iter()
+        .filter(|a| !current.iter().any(|c| c.kind == a.kind))
+        .collect();
+
+    AlertChanges { raised, resolved }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::DateTime;
+
+    use crate::cameras::alerts::{
+        diff, evaluate, Thresholds, BATTERY_EMPTY_SOON, BILLING_ENDING, LOW_BATTERY, PHOTO_QUOTA,
+        SD_CARD_FULL, STALE_TRANSMISSION, WEAK_SIGNAL,
+    };
+    use crate::cameras::tests::camera;
+
+    #[test]
+    fn healthy_camera() {
+        let mut c = camera();
+        c.status.notifications.clear();
+        let now = c.status.last_transmission;
+
+        let alerts = evaluate(&c, &Thresholds::default(), now);
+        assert!(alerts.is_empty());
+    }
+
+    #[test]
+    fn unhealthy_camera() {
+        let mut c = camera();
+        c.status.battery_level = 10;
+        c.status.memory = 29000.0;
+        c.status.signal = 0;
+        let now =
+            DateTime::from_millis(c.status.last_transmission.timestamp_millis() + 48 * 3_600_000);
+
+        let alerts = evaluate(&c, &Thresholds::default(), now);
+
+        let kinds: Vec<&str> = alerts.iter().map(|a| a.kind.as_str()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LOW_BATTERY,
+                SD_CARD_FULL,
+                WEAK_SIGNAL,
+                STALE_TRANSMISSION,
+                "notification:sd_card_one_partition"
+            ]
+        );
+    }
+
+    #[test]
+    fn battery_empty_soon() {
+        let mut c = camera();
+        c.status.notifications.clear();
+        c.status.battery_level = 15;
+        let now = c.status.last_transmission;
+        c.battery_empty_date = Some(DateTime::from_millis(
+            now.timestamp_millis() + 10 * 86_400_000,
+        ));
+
+        let alerts = evaluate(&c, &Thresholds::default(), now);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].kind, LOW_BATTERY);
+        assert!(alerts[0].message.contains("empty around 2024-07-27"));
+        assert_eq!(alerts[1].kind, BATTERY_EMPTY_SOON);
+        assert!(alerts[1].message.contains("in about 10 days"));
+    }
+
+    #[test]
+    fn subscription() {
+        let mut c = camera();
+        c.status.notifications.clear();
+        let now = c.status.last_transmission;
+
+        let sub = c.subscription.as_mut().expect("subscription");
+        sub.photo_count = 95;
+        let end = sub.end_date_billing_cycle.unwrap().timestamp_millis();
+
+        let alerts = evaluate(&c, &Thresholds::default(), now);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, PHOTO_QUOTA);
+
+        // Five days before the end of the billing cycle.
+        let now = DateTime::from_millis(end - 5 * 86_400_000);
+        c.status.last_transmission = now;
+        let alerts = evaluate(&c, &Thresholds::default(), now);
+        assert_eq!(alerts[1].kind, BILLING_ENDING);
+        assert!(alerts[1].message.contains("ends in 5 days"));
+    }
+
+    #[test]
+    fn raise_once_and_resolve() {
+        let mut c = camera();
+        c.status.notifications.clear();
+        c.status.battery_level = 10;
+        let now = c.status.last_transmission;
+        let t = Thresholds::default();
+
+        let changes = diff(vec![], evaluate(&c, &t, now));
+        assert_eq!(changes.raised.len(), 1);
+        assert!(changes.resolved.is_empty());
+
+        // Still low, already notified.
+        let active = changes.raised;
+        let changes = diff(active.clone(), evaluate(&c, &t, now));
+        assert!(changes.raised.is_empty());
+        assert!(changes.resolved.is_empty());
+
+        // Batteries replaced.
+        c.status.battery_level = 100;
+        let changes = diff(active, evaluate(&c, &t, now));
+        assert!(changes.raised.is_empty());
+        assert_eq!(changes.resolved[0].kind, LOW_BATTERY);
+    }
+}
@@ -0,0 +1,247 @@
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+
+/// Sun elevation at sunrise and sunset, accounting for refraction and the sun's radius.
+const SUNRISE_ELEVATION: f64 = -0.833;
+/// Sun elevation at the start and end of civil twilight.
+const CIVIL_TWILIGHT_ELEVATION: f64 = -6.0;
+
+const UNIX_EPOCH_JD: f64 = 2440587.5;
+const J2000_JD: f64 = 2451545.0;
+
+/// Sunrise, sunset and civil twilight for a location and day. Times are None when the sun
+/// doesn't cross the elevation that day (polar day or night).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunTimes {
+    pub solar_noon: DateTime<Utc>,
+    pub civil_dawn: Option<DateTime<Utc>>,
+    pub sunrise: Option<DateTime<Utc>>,
+    pub sunset: Option<DateTime<Utc>>,
+    pub civil_dusk: Option<DateTime<Utc>>,
+}
+
+/// The illuminated fraction and phase of the moon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MoonPhase {
+    pub illumination: f64,
+    pub waxing: bool,
+}
+
+impl MoonPhase {
+    pub fn name(&self) -> &'static str {
+        moon_phase_name(self.illumination, self.waxing)
+    }
+}
+
+fn julian_day(date: DateTime<Utc>) -> f64 {
+    date.timestamp_millis() as f64 / 86_400_000.0 + UNIX_EPOCH_JD
+}
+
+/// Returns the sun's (declination, right ascension) in degrees and the equation of time in
+/// minutes for a julian day.
+fn sun_coordinates(jd: f64) -> (f64, f64, f64) {
+    let n = jd - J2000_JD;
+    let l = (280.460 + 0.9856474 * n).rem_euclid(360.0);
+    let g = (357.528 + 0.9856003 * n).rem_euclid(360.0).to_radians();
+
+    let lambda = (l + 1.915 * g.sin() + 0.020 * (2.0 * g).sin()).to_radians();
+    let epsilon = (23.439 - 0.0000004 * n).to_radians();
+
+    let ra = (epsilon.cos() * lambda.sin())
+        .atan2(lambda.cos())
+        .to_degrees()
+        .rem_euclid(360.0);
+    let dec = (epsilon.sin() * lambda.sin()).asin().to_degrees();
+
+    let mut eot = l - ra;
+    if eot > 180.0 {
+        eot -= 360.0;
+    } else if eot < -180.0 {
+        eot += 360.0;
+    }
+
+    (dec, ra, eot * 4.0)
+}
+
+/// Returns the elevation of the sun in degrees above the horizon.
+///
+/// Arguments:
+///
+/// date: The time of the observation.
+/// latitude: Latitude of the observer in degrees.
+/// longitude: Longitude of the observer in degrees, east positive.
+pub fn sun_elevation(date: DateTime<Utc>, latitude: f64, longitude: f64) -> f64 {
+    let jd = julian_day(date);
+    let (dec, ra, _) = sun_coordinates(jd);
+
+    let gmst = (18.697374558 + 24.06570982441908 * (jd - J2000_JD)).rem_euclid(24.0);
+    let hour_angle = (gmst * 15.0 + longitude - ra).to_radians();
+
+    let lat = latitude.to_radians();
+    let dec = dec.to_radians();
+
+    (lat.sin() * dec.sin() + lat.cos() * dec.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees()
+}
+
+/// Returns the sun times of the solar day closest to the date at the location.
+///
+/// Arguments:
+///
+/// date: A time within the day.
+/// latitude: Latitude of the observer in degrees.
+/// longitude: Longitude of the observer in degrees, east positive.
+pub fn sun_times(date: DateTime<Utc>, latitude: f64, longitude: f64) -> SunTimes {
+    // Mean solar noon of the UTC day, moved to the one closest to the date.
+    let midday = date.date_naive().and_time(NaiveTime::MIN).and_utc() + Duration::hours(12);
+    let mut noon = midday - Duration::seconds((longitude / 15.0 * 3600.0) as i64);
+    if date - noon > Duration::hours(12) {
+        noon += Duration::days(1);
+    } else if noon - date > Duration::hours(12) {
+        noon -= Duration::days(1);
+    }
+
+    let (dec, _, eot) = sun_coordinates(julian_day(noon));
+    let solar_noon = noon - Duration::seconds((eot * 60.0) as i64);
+
+    let event = |elevation: f64, sign: f64| -> Option<DateTime<Utc>> {
+        let lat = latitude.to_radians();
+        let d = dec.to_radians();
+        let cos_h = (elevation.to_radians().sin() - lat.sin() * d.sin()) / (lat.cos() * d.cos());
+        if !(-1.0..=1.0).contains(&cos_h) {
+            return None;
+        }
+
+        let hours = cos_h.acos().to_degrees() / 15.0;
+        Some(solar_noon + Duration::seconds((sign * hours * 3600.0) as i64))
+    };
+
+    SunTimes {
+        solar_noon,
+        civil_dawn: event(CIVIL_TWILIGHT_ELEVATION, -1.0),
+        sunrise: event(SUNRISE_ELEVATION, -1.0),
+        sunset: event(SUNRISE_ELEVATION, 1.0),
+        civil_dusk: event(CIVIL_TWILIGHT_ELEVATION, 1.0),
+    }
+}
+
+/// Returns the phase of the sun at the location: "night", "dawn", "day" or "dusk". Dawn and
+/// dusk are the civil twilight before sunrise and after sunset.
+///
+/// Arguments:
+///
+/// date: The time of the observation.
+/// latitude: Latitude of the observer in degrees.
+/// longitude: Longitude of the observer in degrees, east positive.
+pub fn sun_phase(date: DateTime<Utc>, latitude: f64, longitude: f64) -> &'static str {
+    let elevation = sun_elevation(date, latitude, longitude);
+    if elevation >= SUNRISE_ELEVATION {
+        return "day";
+    }
+    if elevation < CIVIL_TWILIGHT_ELEVATION {
+        return "night";
+    }
+
+    let times = sun_times(date, latitude, longitude);
+    if date < times.solar_noon {
+        "dawn"
+    } else {
+        "dusk"
+    }
+}
+
+/// Returns the phase of the moon, using the low precision lunar terms from Meeus.
+///
+/// Arguments:
+///
+/// date: The time of the observation.
+pub fn moon_phase(date: DateTime<Utc>) -> MoonPhase {
+    let t = (julian_day(date) - J2000_JD) / 36525.0;
+
+    let d = (297.8501921 + 445267.1114034 * t).rem_euclid(360.0);
+    let m = (357.5291092 + 35999.0502909 * t).rem_euclid(360.0);
+    let mp = (134.9633964 + 477198.8675055 * t).rem_euclid(360.0);
+
+    let (dr, mr, mpr) = (d.to_radians(), m.to_radians(), mp.to_radians());
+    let phase_angle = 180.0 - d - 6.289 * mpr.sin() + 2.100 * mr.sin()
+        - 1.274 * (2.0 * dr - mpr).sin()
+        - 0.658 * (2.0 * dr).sin()
+        - 0.214 * (2.0 * mpr).sin()
+        - 0.110 * dr.sin();
+
+    MoonPhase {
+        illumination: (1.0 + phase_angle.to_radians().cos()) / 2.0,
+        waxing: d < 180.0,
+    }
+}
+
+/// Returns the phase name for an illuminated fraction of the moon.
+pub fn moon_phase_name(illumination: f64, waxing: bool) -> &'static str {
+    match (illumination, waxing) {
+        (i, _) if i < 0.03 => "new moon",
+        (i, _) if i > 0.97 => "full moon",
+        (i, true) if i < 0.45 => "waxing crescent",
+        (i, false) if i < 0.45 => "waning crescent",
+        (i, true) if i <= 0.55 => "first quarter",
+        (i, false) if i <= 0.55 => "last quarter",
+        (_, true) => "waxing gibbous",
+        (_, false) => "waning gibbous",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use crate::cameras::astro::{moon_phase, moon_phase_name, sun_phase, sun_times};
+
+    fn date(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn sun_times_miami() {
+        // Miami, 2024-07-17: sunrise 06:43 EDT, sunset 20:15 EDT.
+        let times = sun_times(date("2024-07-17T17:00:00Z"), 25.544241, -80.439992);
+
+        let sunrise = times.sunrise.unwrap();
+        let sunset = times.sunset.unwrap();
+        assert!((sunrise - date("2024-07-17T10:43:00Z")).num_minutes().abs() <= 5);
+        assert!((sunset - date("2024-07-18T00:15:00Z")).num_minutes().abs() <= 5);
+        assert!(times.civil_dawn.unwrap() < sunrise);
+        assert!(times.civil_dusk.unwrap() > sunset);
+    }
+
+    #[test]
+    fn sun_phases() {
+        let (lat, lng) = (25.544241, -80.439992);
+        assert_eq!(sun_phase(date("2024-07-17T19:51:41Z"), lat, lng), "day");
+        assert_eq!(sun_phase(date("2024-07-18T05:00:00Z"), lat, lng), "night");
+        assert_eq!(sun_phase(date("2024-07-17T10:30:00Z"), lat, lng), "dawn");
+        assert_eq!(sun_phase(date("2024-07-18T00:25:00Z"), lat, lng), "dusk");
+    }
+
+    #[test]
+    fn moon_phases() {
+        // Full moon 2024-07-21 10:17 UTC, new moon 2024-07-05 22:57 UTC.
+        assert_eq!(moon_phase(date("2024-07-21T10:17:00Z")).name(), "full moon");
+        assert_eq!(moon_phase(date("2024-07-05T22:57:00Z")).name(), "new moon");
+        assert_eq!(
+            moon_phase(date("2024-07-17T19:51:41Z")).name(),
+            "waxing gibbous"
+        );
+        assert_eq!(
+            moon_phase(date("2024-07-28T02:51:00Z")).name(),
+            "last quarter"
+        );
+    }
+
+    #[test]
+    fn phase_names() {
+        assert_eq!(moon_phase_name(0.0, true), "new moon");
+        assert_eq!(moon_phase_name(0.25, true), "waxing crescent");
+        assert_eq!(moon_phase_name(0.5, false), "last quarter");
+        assert_eq!(moon_phase_name(0.8, false), "waning gibbous");
+        assert_eq!(moon_phase_name(1.0, false), "full moon");
+    }
+}
@@ -0,0 +1,511 @@
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use image::{DynamicImage, GenericImageView, GrayImage};
+use log::debug;
+
+use crate::cameras::astro::moon_phase_name;
+
+/// Default height of the info strip as a ratio of the picture height.
+pub const BANNER_HEIGHT_RATIO: f32 = 0.065;
+
+/// Pixels at or above this luma are treated as banner text.
+const INK_THRESHOLD: u8 = 128;
+/// Pixels at or below this luma are treated as banner background.
+const BACKGROUND_THRESHOLD: u8 = 48;
+/// Minimum share of background pixels for a region to be considered a banner.
+const MIN_BACKGROUND_FRACTION: f32 = 0.6;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+/// Maximum number of differing cells for a glyph to match a template.
+const MAX_GLYPH_DISTANCE: u32 = 7;
+
+/// 5x7 templates of the characters printed in the info strip.
+#[rustfmt::skip]
+const TEMPLATES: [(char, [&str; GLYPH_HEIGHT]); 17] = [
+    ('0', [".###.", "#...#", "#..##", "#.#.#", "##..#", "#...#", ".###."]),
+    ('1', ["..#..", ".##..", "..#..", "..#..", "..#..", "..#..", ".###."]),
+    ('2', [".###.", "#...#", "....#", "...#.", "..#..", ".#...", "#####"]),
+    ('3', ["#####", "...#.", "..#..", "...#.", "....#", "#...#", ".###."]),
+    ('4', ["...#.", "..##.", ".#.#.", "#..#.", "#####", "...#.", "...#."]),
+    ('5', ["#####", "#....", "####.", "....#", "....#", "#...#", ".###."]),
+    ('6', ["..##.", ".#...", "#....", "####.", "#...#", "#...#", ".###."]),
+    ('7', ["#####", "....#", "...#.", "..#..", ".#...", ".#...", ".#..."]),
+    ('8', [".###.", "#...#", "#...#", ".###.", "#...#", "#...#", ".###."]),
+    ('9', [".###.", "#...#", "#...#", ".####", "....#", "...#.", ".##.."]),
+    ('/', ["....#", "....#", "...#.", "..#..", ".#...", "#....", "#...."]),
+    (':', [".....", "..#..", "..#..", ".....", "..#..", "..#..", "....."]),
+    ('A', [".###.", "#...#", "#...#", "#####", "#...#", "#...#", "#...#"]),
+    ('P', ["####.", "#...#", "#...#", "####.", "#....", "#....", "#...."]),
+    ('M', ["#...#", "##.##", "#.#.#", "#.#.#", "#...#", "#...#", "#...#"]),
+    ('F', ["#####", "#....", "#....", "####.", "#....", "#....", "#...."]),
+    ('C', [".###.", "#...#", "#....", "#....", "#....", "#...#", ".###."]),
+];
+
+/// Data read from the info strip imprinted at the bottom of a picture.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Banner {
+    pub text: String,
+    /// Temperature in Fahrenheit.
+    pub temperature: Option<i64>,
+    pub moon_phase: String,
+    pub moon_illumination: Option<f64>,
+    pub timestamp: String,
+}
+
+impl Banner {
+    /// Returns the timestamp as RFC 3339, None when it has no time or doesn't parse. The camera
+    /// prints local time without a zone, it's taken as UTC like an EXIF date without offset.
+    pub fn timestamp_rfc3339(&self) -> Option<String> {
+        ["%m/%d/%Y %I:%M %p", "%m/%d/%Y %H:%M"]
+            .iter()
+            .find_map(|f| NaiveDateTime::parse_from_str(&self.timestamp, f).ok())
+            .map(|t| Utc.from_utc_datetime(&t).to_rfc3339())
+    }
+}
+
+/// A connected run of ink columns in the banner.
+#[derive(Debug, Clone, Copy)]
+struct Glyph {
+    x0: u32,
+    x1: u32,
+    y0: u32,
+    y1: u32,
+}
+
+impl Glyph {
+    fn width(&self) -> u32 {
+        self.x1 - self.x0 + 1
+    }
+
+    fn height(&self) -> u32 {
+        self.y1 - self.y0 + 1
+    }
+}
+
+/// Returns the height of the info strip when the bottom of the picture looks like one.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+/// ratio: The height of the banner as a ratio of the picture height.
+pub fn banner_height(img: &DynamicImage, ratio: f32) -> Option<u32> {
+    let (width, height) = img.dimensions();
+    let banner = (height as f32 * ratio).round() as u32;
+    if banner == 0 || banner >= height || width == 0 {
+        return None;
+    }
+
+    let gray = img.crop_imm(0, height - banner, width, banner).to_luma8();
+    let background = gray
+        .pixels()
+        .filter(|p| p[0] <= BACKGROUND_THRESHOLD)
+        .count();
+
+    let fraction = background as f32 / (width * banner) as f32;
+    debug!("banner::banner_height background fraction {}", fraction);
+    if fraction < MIN_BACKGROUND_FRACTION {
+        return None;
+    }
+
+    Some(banner)
+}
+
+/// Splits a picture in the picture without the info strip and the info strip itself. Returns
+/// None when no banner was found.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+/// ratio: The height of the banner as a ratio of the picture height.
+pub fn split_banner(img: &DynamicImage, ratio: f32) -> Option<(DynamicImage, DynamicImage)> {
+    let banner = banner_height(img, ratio)?;
+    let (width, height) = img.dimensions();
+
+    Some((
+        img.crop_imm(0, 0, width, height - banner),
+        img.crop_imm(0, height - banner, width, banner),
+    ))
+}
+
+/// Reads the temperature, moon phase and timestamp printed in the info strip.
+///
+/// Arguments:
+///
+/// banner: The info strip cropped from the picture.
+pub fn read_banner(banner: &DynamicImage) -> Banner {
+    let gray = banner.to_luma8();
+    let glyphs = segment(&gray);
+    if glyphs.is_empty() {
+        return Banner::default();
+    }
+
+    let mut heights: Vec<u32> = glyphs.iter().map(|g| g.height()).collect();
+    heights.sort_unstable();
+    let text_height = heights[heights.len() / 2];
+
+    let mut result = Banner::default();
+    let mut text = String::new();
+    let mut prev: Option<Glyph> = None;
+
+    for glyph in glyphs {
+        if let Some(p) = prev {
+            if glyph.x0 - p.x1 > text_height / 2 {
+                text.push(' ');
+            }
+        }
+        prev = Some(glyph);
+
+        // The moon icon is taller than the text and nearly round.
+        if glyph.height() as f32 > text_height as f32 * 1.3
+            && glyph.width() as f32 >= glyph.height() as f32 * 0.3
+        {
+            let (illumination, waxing) = moon_illumination(&gray, glyph);
+            result.moon_illumination = Some(illumination);
+            result.moon_phase = moon_phase_name(illumination, waxing).to_string();
+            continue;
+        }
+
+        text.push(classify(&gray, glyph, text_height));
+    }
+
+    result.temperature = parse_temperature(&text);
+    result.timestamp = parse_timestamp(&text);
+    result.text = text;
+
+    debug!("banner::read_banner {:?}", result);
+    result
+}
+
+fn is_ink(gray: &GrayImage, x: u32, y: u32) -> bool {
+    gray.get_pixel(x, y)[0] >= INK_THRESHOLD
+}
+
+/// Splits the banner into glyphs on columns without any ink.
+fn segment(gray: &GrayImage) -> Vec<Glyph> {
+    let (width, height) = gray.dimensions();
+    let mut glyphs = Vec::new();
+    let mut start: Option<u32> = None;
+
+    for x in 0..=width {
+        let has_ink = x < width && (0..height).any(|y| is_ink(gray, x, y));
+        match (has_ink, start) {
+            (true, None) => start = Some(x),
+            (false, Some(s)) => {
+                let rows: Vec<u32> = (0..height)
+                    .filter(|&y| (s..x).any(|c| is_ink(gray, c, y)))
+                    .collect();
+                glyphs.push(Glyph {
+                    x0: s,
+                    x1: x - 1,
+                    y0: rows[0],
+                    y1: rows[rows.len() - 1],
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    glyphs
+}
+
+fn classify(gray: &GrayImage, glyph: Glyph, text_height: u32) -> char {
+    let height = glyph.height() as f32;
+    let line = text_height as f32;
+
+    // Small marks are told apart by their position on the line.
+    if height < line * 0.5 {
+        let center = (glyph.y0 + glyph.y1) as f32 / 2.0;
+        let rows: Vec<u32> = (0..gray.height())
+            .filter(|&y| (0..gray.width()).any(|x| is_ink(gray, x, y)))
+            .collect();
+        let middle = (rows[0] + rows[rows.len() - 1]) as f32 / 2.0;
+
+        return if center < middle && glyph.width() <= glyph.height() + 1 {
+            '°'
+        } else if glyph.width() > glyph.height() {
+            '-'
+        } else {
+            '.'
+        };
+    }
+
+    let cells = normalize(gray, glyph);
+    let mut best = ('?', u32::MAX);
+    for (c, template) in TEMPLATES.iter() {
+        let mut distance = 0;
+        for (row, line) in template.iter().enumerate() {
+            for (col, cell) in line.chars().enumerate() {
+                if (cell == '#') != cells[row][col] {
+                    distance += 1;
+                }
+            }
+        }
+
+        if distance < best.1 {
+            best = (*c, distance);
+        }
+    }
+
+    if best.1 > MAX_GLYPH_DISTANCE {
+        return '?';
+    }
+
+    best.0
+}
+
+/// Scales a glyph down to the template grid. Narrow glyphs keep their aspect ratio and are
+/// centered, so a '1' or ':' isn't stretched into a block.
+fn normalize(gray: &GrayImage, glyph: Glyph) -> [[bool; GLYPH_WIDTH]; GLYPH_HEIGHT] {
+    let mut cells = [[false; GLYPH_WIDTH]; GLYPH_HEIGHT];
+
+    let cell_height = glyph.height() as f32 / GLYPH_HEIGHT as f32;
+    let box_width = (glyph.width() as f32).max(cell_height * GLYPH_WIDTH as f32);
+    let cell_width = box_width / GLYPH_WIDTH as f32;
+    let left = glyph.x0 as f32 - (box_width - glyph.width() as f32) / 2.0;
+
+    for (row, line) in cells.iter_mut().enumerate() {
+        for (col, cell) in line.iter_mut().enumerate() {
+            let x0 = left + col as f32 * cell_width;
+            let y0 = glyph.y0 as f32 + row as f32 * cell_height;
+
+            let mut ink = 0;
+            let mut total = 0;
+            let mut y = y0.floor() as i64;
+            while (y as f32) < y0 + cell_height && y <= glyph.y1 as i64 {
+                let mut x = x0.floor() as i64;
+                while (x as f32) < x0 + cell_width {
+                    if x >= glyph.x0 as i64
+                        && x <= glyph.x1 as i64
+                        && y >= 0
+                        && is_ink(gray, x as u32, y as u32)
+                    {
+                        ink += 1;
+                    }
+                    total += 1;
+                    x += 1;
+                }
+                y += 1;
+            }
+
+            *cell = total > 0 && ink as f32 / total as f32 >= 0.4;
+        }
+    }
+
+    cells
+}
+
+/// Estimates the lit fraction of the moon icon and whether the lit side is the right (waxing)
+/// side, by checking which edge of the icon follows the outline of a full disc.
+fn moon_illumination(gray: &GrayImage, glyph: Glyph) -> (f64, bool) {
+    let radius = (glyph.height() as f64 - 1.0) / 2.0;
+    let center_y = (glyph.y0 + glyph.y1) as f64 / 2.0;
+
+    let mut lit = 0;
+    let mut edges = Vec::new();
+    for y in glyph.y0..=glyph.y1 {
+        let xs: Vec<u32> = (glyph.x0..=glyph.x1)
+            .filter(|&x| is_ink(gray, x, y))
+            .collect();
+        lit += xs.len();
+        if let (Some(l), Some(r)) = (xs.first(), xs.last()) {
+            edges.push((y, *l as f64, *r as f64));
+        }
+    }
+
+    let disc = std::f64::consts::PI * radius * radius;
+    let illumination = (lit as f64 / disc).min(1.0);
+
+    let left_most = edges.iter().map(|e| e.1).fold(f64::MAX, f64::min);
+    let right_most = edges.iter().map(|e| e.2).fold(f64::MIN, f64::max);
+
+    let mut left_err = 0.0;
+    let mut right_err = 0.0;
+    for (y, l, r) in edges {
+        let dy = (y as f64 - center_y).abs().min(radius);
+        let expected = radius - (radius * radius - dy * dy).sqrt();
+        left_err += (l - left_most - expected).powi(2);
+        right_err += (right_most - r - expected).powi(2);
+    }
+
+    (illumination, right_err <= left_err)
+}
+
+/// Finds a temperature such as "86°F" or "-3°C" and returns it in Fahrenheit.
+fn parse_temperature(text: &str) -> Option<i64> {
+    let chars: Vec<char> = text.chars().collect();
+
+    for (i, c) in chars.iter().enumerate() {
+        let unit = match c {
+            '°' => chars.get(i + 1).copied().unwrap_or('F'),
+            'F' | 'C' => *c,
+            _ => continue,
+        };
+
+        let mut start = i;
+        while start > 0 && chars[start - 1].is_ascii_digit() {
+            start -= 1;
+        }
+        if start == i {
+            continue;
+        }
+        if start > 0 && chars[start - 1] == '-' {
+            start -= 1;
+        }
+
+        let value: i64 = chars[start..i].iter().collect::<String>().parse().ok()?;
+        return match unit {
+            'C' => Some((value as f64 * 9.0 / 5.0 + 32.0).round() as i64),
+            _ => Some(value),
+        };
+    }
+
+    None
+}
+
+/// Finds a timestamp such as "07/17/2024 07:51 PM".
+fn parse_timestamp(text: &str) -> String {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+
+    for (i, token) in tokens.iter().enumerate() {
+        let date: Vec<&str> = token.split('/').collect();
+        if date.len() != 3 || date.iter().any(|d| d.is_empty() || !is_digits(d)) {
+            continue;
+        }
+
+        let mut timestamp = token.to_string();
+        if let Some(time) = tokens.get(i + 1) {
+            if time.contains(':') && time.split(':').all(is_digits) {
+                timestamp = format!("{} {}", timestamp, time);
+                if let Some(m) = tokens.get(i + 2) {
+                    if *m == "AM" || *m == "PM" {
+                        timestamp = format!("{} {}", timestamp, m);
+                    }
+                }
+            }
+        }
+
+        return timestamp;
+    }
+
+    String::new()
+}
+
+fn is_digits(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GrayImage, Luma};
+
+    use crate::cameras::banner::{
+        read_banner, split_banner, BANNER_HEIGHT_RATIO, GLYPH_HEIGHT, TEMPLATES,
+    };
+
+    const SCALE: u32 = 3;
+    const TOP: u32 = 10;
+
+    /// Draws text with the glyph templates the way the camera prints it.
+    fn draw_banner(text: &str, moon: bool) -> GrayImage {
+        let mut img = GrayImage::new(600, 40);
+        let mut x = 5;
+
+        for c in text.chars() {
+            match c {
+                ' ' => x += 4 * SCALE,
+                '°' => {
+                    for dy in 0..SCALE {
+                        for dx in 0..SCALE {
+                            img.put_pixel(x + dx, TOP + dy, Luma([255]));
+                        }
+                    }
+                    x += 2 * SCALE;
+                }
+                '-' => {
+                    for dy in 0..SCALE {
+                        for dx in 0..4 * SCALE {
+                            img.put_pixel(x + dx, TOP + 3 * SCALE + dy, Luma([255]));
+                        }
+                    }
+                    x += 5 * SCALE;
+                }
+                _ => {
+                    let (_, template) = TEMPLATES.iter().find(|(t, _)| *t == c).unwrap();
+                    for (row, line) in template.iter().enumerate() {
+                        for (col, cell) in line.chars().enumerate() {
+                            if cell != '#' {
+                                continue;
+                            }
+                            for dy in 0..SCALE {
+                                for dx in 0..SCALE {
+                                    img.put_pixel(
+                                        x + col as u32 * SCALE + dx,
+                                        TOP + row as u32 * SCALE + dy,
+                                        Luma([255]),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    x += 6 * SCALE;
+                }
+            }
+        }
+
+        if moon {
+            let r = (GLYPH_HEIGHT as i64 * SCALE as i64 * 3) / 4;
+            let (cx, cy) = (x as i64 + 10 + r, 20);
+            for y in cy - r..=cy + r {
+                for xx in cx - r..=cx + r {
+                    if (xx - cx).pow(2) + (y - cy).pow(2) <= r * r {
+                        img.put_pixel(xx as u32, y as u32, Luma([255]));
+                    }
+                }
+            }
+        }
+
+        img
+    }
+
+    #[test]
+    fn read_info_strip() {
+        let img = DynamicImage::ImageLuma8(draw_banner("86°F 07/17/2024 07:51 PM", true));
+
+        let banner = read_banner(&img);
+
+        assert_eq!(banner.temperature, Some(86));
+        assert_eq!(banner.timestamp, "07/17/2024 07:51 PM");
+        assert_eq!(
+            banner.timestamp_rfc3339().as_deref(),
+            Some("2024-07-17T19:51:00+00:00")
+        );
+        assert_eq!(banner.moon_phase, "full moon");
+    }
+
+    #[test]
+    fn read_celsius() {
+        let img = DynamicImage::ImageLuma8(draw_banner("-5°C", false));
+
+        let banner = read_banner(&img);
+
+        assert_eq!(banner.temperature, Some(23));
+        assert!(banner.moon_phase.is_empty());
+    }
+
+    #[test]
+    fn split_picture() {
+        let mut picture = GrayImage::from_pixel(600, 600, Luma([200]));
+        let strip = draw_banner("86°F", false);
+        image::imageops::replace(&mut picture, &strip, 0, 561);
+
+        let (body, banner) =
+            split_banner(&DynamicImage::ImageLuma8(picture), BANNER_HEIGHT_RATIO).expect("banner");
+
+        assert_eq!(body.height() + banner.height(), 600);
+        assert_eq!(read_banner(&banner).temperature, Some(86));
+
+        let plain = DynamicImage::ImageLuma8(GrayImage::from_pixel(600, 600, Luma([200])));
+        assert!(split_banner(&plain, BANNER_HEIGHT_RATIO).is_none());
+    }
+}
@@ -0,0 +1,60 @@
+/// Mobile networks by mobile country code (mcc) and mobile network code (mnc).
+const NETWORKS: [(i64, i64, &str); 30] = [
+    // United States
+    (310, 4, "Verizon"),
+    (310, 12, "Verizon"),
+    (311, 480, "Verizon"),
+    (310, 150, "AT&T"),
+    (310, 170, "AT&T"),
+    (310, 280, "AT&T"),
+    (310, 380, "AT&T"),
+    (310, 410, "AT&T"),
+    (310, 560, "AT&T"),
+    (310, 680, "AT&T"),
+    (313, 100, "AT&T FirstNet"),
+    (310, 120, "T-Mobile"),
+    (310, 160, "T-Mobile"),
+    (310, 200, "T-Mobile"),
+    (310, 210, "T-Mobile"),
+    (310, 220, "T-Mobile"),
+    (310, 230, "T-Mobile"),
+    (310, 240, "T-Mobile"),
+    (310, 250, "T-Mobile"),
+    (310, 260, "T-Mobile"),
+    (310, 270, "T-Mobile"),
+    (310, 310, "T-Mobile"),
+    (310, 490, "T-Mobile"),
+    (310, 660, "T-Mobile"),
+    (310, 800, "T-Mobile"),
+    (312, 250, "T-Mobile"),
+    // Canada
+    (302, 220, "Telus"),
+    (302, 610, "Bell"),
+    (302, 720, "Rogers"),
+    (302, 780, "SaskTel"),
+];
+
+/// Returns the name of the mobile network, None when the mcc and mnc aren't known.
+///
+/// Arguments:
+///
+/// mcc: Mobile country code.
+/// mnc: Mobile network code.
+pub fn carrier_name(mcc: i64, mnc: i64) -> Option<&'static str> {
+    NETWORKS
+        .iter()
+        .find(|n| n.0 == mcc && n.1 == mnc)
+        .map(|n| n.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cameras::carrier::carrier_name;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(carrier_name(311, 480), Some("Verizon"));
+        assert_eq!(carrier_name(310, 410), Some("AT&T"));
+        assert_eq!(carrier_name(0, 0), None);
+    }
+}
@@ -0,0 +1,500 @@
+use std::fmt::Debug;
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Label of a picture without detections.
+pub const EMPTY: &str = "empty";
+
+/// Labels of the models we run. Models trained on our species output deer, buck, turkey and
+/// hog, MegaDetector outputs animal, human and vehicle.
+pub const LABELS: [&str; 8] = [
+    "deer", "buck", "turkey", "hog", "animal", "human", "vehicle", EMPTY,
+];
+
+/// Checks the labels of a model's classes against LABELS, a typo would store labels no query
+/// finds. EMPTY is the label of pictures without detections, not a class.
+///
+/// Arguments:
+///
+/// labels: The labels of the class scores of the model, in order.
+pub fn check_labels(labels: &[String]) -> crate::Result<()> {
+    if labels.is_empty() {
+        return Err(Box::from("detect::check_labels no labels"));
+    }
+
+    for label in labels {
+        if label == EMPTY || !LABELS.contains(&label.as_str()) {
+            return Err(Box::from(format!(
+                "detect::check_labels unknown label {:?}, expected one of {:?}",
+                label, LABELS
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Detections that overlap a more confident one of the same label by more than this are
+/// dropped.
+const NMS_IOU: f32 = 0.45;
+
+/// Padding of the letterboxed input, the gray YOLO models are trained with.
+const PAD_VALUE: f32 = 114.0 / 255.0;
+
+/// A box in the picture, relative to its width and height.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct BoundingBox {
+    /// Left edge, 0 to 1.
+    pub x: f32,
+    /// Top edge, 0 to 1.
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    fn area(&self) -> f32 {
+        self.width * self.height
+    }
+
+    /// Intersection over union of two boxes.
+    fn iou(&self, other: &BoundingBox) -> f32 {
+        let w = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+        let h = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+        if w <= 0.0 || h <= 0.0 {
+            return 0.0;
+        }
+
+        let intersection = w * h;
+        intersection / (self.area() + other.area() - intersection)
+    }
+}
+
+/// Something found in a picture.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Detection {
+    pub label: String,
+    pub confidence: f32,
+    pub bbox: BoundingBox,
+}
+
+/// The detections of a model in a picture.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Detections {
+    /// Name of the model.
+    pub model: String,
+    /// The distinct labels found, "empty" when nothing was found. Indexed for queries.
+    pub labels: Vec<String>,
+    pub boxes: Vec<Detection>,
+}
+
+impl Detections {
+    /// Arguments:
+    ///
+    /// model: Name of the model.
+    /// boxes: The detections of the model.
+    pub fn new(model: &str, boxes: Vec<Detection>) -> Self {
+        let mut labels: Vec<String> = Vec::new();
+        for b in &boxes {
+            if !labels.contains(&b.label) {
+                labels.push(b.label.clone());
+            }
+        }
+        if labels.is_empty() {
+            labels.push(EMPTY.to_string());
+        }
+
+        Detections {
+            model: model.to_string(),
+            labels,
+            boxes,
+        }
+    }
+}
+
+/// A model that finds animals, people and vehicles in pictures. Inference runs on the CPU.
+pub trait Detector: Debug + Send + Sync {
+    /// Name of the model, stored with the detections.
+    fn name(&self) -> &str;
+
+    /// Returns the detections in the picture.
+    ///
+    /// Arguments:
+    ///
+    /// img: The decoded picture.
+    fn detect(&self, img: &DynamicImage) -> crate::Result<Vec<Detection>>;
+}
+
+/// Scaling of a picture into the square input of a model, keeping the aspect ratio and padding
+/// the rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Letterbox {
+    /// Width and height of the model input.
+    pub size: u32,
+    pub scale: f32,
+    pub pad_x: f32,
+    pub pad_y: f32,
+    /// Size of the picture.
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Letterbox {
+    /// Arguments:
+    ///
+    /// width: Width of the picture.
+    /// height: Height of the picture.
+    /// size: Width and height of the model input.
+    pub fn new(width: u32, height: u32, size: u32) -> Self {
+        let scale = (size as f32 / width as f32).min(size as f32 / height as f32);
+
+        Letterbox {
+            size,
+            scale,
+            pad_x: (size as f32 - width as f32 * scale) / 2.0,
+            pad_y: (size as f32 - height as f32 * scale) / 2.0,
+            width,
+            height,
+        }
+    }
+
+    /// Returns the picture as a 1x3xSxS tensor of RGB values from 0 to 1, in channel order.
+    ///
+    /// Arguments:
+    ///
+    /// img: The decoded picture.
+    pub fn tensor(&self, img: &DynamicImage) -> Vec<f32> {
+        let size = self.size as usize;
+        let w = ((self.width as f32 * self.scale).round() as u32).clamp(1, self.size);
+        let h = ((self.height as f32 * self.scale).round() as u32).clamp(1, self.size);
+        let resized = img.resize_exact(w, h, FilterType::Triangle).to_rgb8();
+
+        let left = self.pad_x.floor() as usize;
+        let top = self.pad_y.floor() as usize;
+        let mut data = vec![PAD_VALUE; 3 * size * size];
+        for (x, y, p) in resized.enumerate_pixels() {
+            let (x, y) = (x as usize + left, y as usize + top);
+            if x >= size || y >= size {
+                continue;
+            }
+            for c in 0..3 {
+                data[c * size * size + y * size + x] = p[c] as f32 / 255.0;
+            }
+        }
+
+        data
+    }
+
+    /// Returns a box of the model input, center and size in input pixels, relative to the
+    /// picture.
+    pub fn picture_box(&self, cx: f32, cy: f32, w: f32, h: f32) -> BoundingBox {
+        let x0 = ((cx - w / 2.0 - self.pad_x) / self.scale).clamp(0.0, self.width as f32);
+        let y0 = ((cy - h / 2.0 - self.pad_y) / self.scale).clamp(0.0, self.height as f32);
+        let x1 = ((cx + w / 2.0 - self.pad_x) / self.scale).clamp(0.0, self.width as f32);
+        let y1 = ((cy + h / 2.0 - self.pad_y) / self.scale).clamp(0.0, self.height as f32);
+
+        BoundingBox {
+            x: x0 / self.width as f32,
+            y: y0 / self.height as f32,
+            width: (x1 - x0) / self.width as f32,
+            height: (y1 - y0) / self.height as f32,
+        }
+    }
+}
+
+/// Returns the detections of YOLO output rows, [cx, cy, w, h, objectness, class scores...],
+/// with a confidence of at least threshold.
+///
+/// Arguments:
+///
+/// rows: The output rows of the model.
+/// labels: The labels of the class scores, in order.
+/// threshold: The minimum confidence.
+/// letterbox: The scaling of the picture into the model input.
+pub fn decode<'a>(
+    rows: impl Iterator<Item = &'a [f32]>,
+    labels: &[String],
+    threshold: f32,
+    letterbox: &Letterbox,
+) -> Vec<Detection> {
+    let mut detections = Vec::new();
+    for row in rows {
+        if row.len() < 5 + labels.len() {
+            continue;
+        }
+
+        let Some((class, score)) = row[5..5 + labels.len()]
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+        else {
+            continue;
+        };
+
+        let confidence = row[4] * score;
+        if confidence < threshold {
+            continue;
+        }
+
+        detections.push(Detection {
+            label: labels[class].clone(),
+            confidence,
+            bbox: letterbox.picture_box(row[0], row[1], row[2], row[3]),
+        });
+    }
+
+    detections
+}
+
+/// Returns the detections without the ones that overlap a more confident detection of the same
+/// label, most confident first.
+///
+/// Arguments:
+///
+/// detections: The detections of a picture.
+pub fn non_max_suppression(mut detections: Vec<Detection>) -> Vec<Detection> {
+    detections.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+    let mut kept: Vec<Detection> = Vec::new();
+    for d in detections {
+        let overlaps = kept
+            .iter()
+            .any(|k| k.label == d.label && k.bbox.iou(&d.bbox) > NMS_IOU);
+        if !overlaps {
+            kept.push(d);
+        }
+    }
+
+    kept
+}
+
+#[cfg(feature = "detect")]
+pub use onnx::OnnxDetector;
+
+#[cfg(feature = "detect")]
+mod onnx {
+    use std::env;
+    use std::fmt;
+
+    use image::{DynamicImage, GenericImageView};
+    use log::debug;
+    use ort::session::Session;
+    use ort::value::Tensor;
+
+    use crate::cameras::detect::{
+        check_labels, decode, non_max_suppression, Detection, Detector, Letterbox,
+    };
+
+    /// Input size of MegaDetector v5.
+    const DEFAULT_INPUT_SIZE: u32 = 640;
+    /// Confidence MegaDetector recommends for camera trap pictures.
+    const DEFAULT_CONFIDENCE: f32 = 0.2;
+    /// Classes of MegaDetector v5.
+    const DEFAULT_LABELS: &str = "animal,human,vehicle";
+
+    /// A YOLO model in ONNX format run locally on the CPU, e.g. MegaDetector v5.
+    pub struct OnnxDetector {
+        session: Session,
+        name: String,
+        labels: Vec<String>,
+        size: u32,
+        threshold: f32,
+    }
+
+    impl fmt::Debug for OnnxDetector {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("OnnxDetector")
+                .field("name", &self.name)
+                .field("labels", &self.labels)
+                .field("size", &self.size)
+                .field("threshold", &self.threshold)
+                .finish()
+        }
+    }
+
+    impl OnnxDetector {
+        /// Loads a model.
+        ///
+        /// Arguments:
+        ///
+        /// path: Path of the ONNX model.
+        /// labels: The labels of the class scores of the model, in order.
+        /// size: Width and height of the model input.
+        /// threshold: The minimum confidence of a detection.
+        pub fn new(
+            path: &str,
+            labels: Vec<String>,
+            size: u32,
+            threshold: f32,
+        ) -> crate::Result<Self> {
+            check_labels(&labels)?;
+            let session = Session::builder()?.commit_from_file(path)?;
+            let name = path
+                .rsplit('/')
+                .next()
+                .unwrap_or(path)
+                .trim_end_matches(".onnx")
+                .to_string();
+
+            Ok(OnnxDetector {
+                session,
+                name,
+                labels,
+                size,
+                threshold,
+            })
+        }
+
+        /// Loads the model at DETECT_MODEL, with DETECT_LABELS, DETECT_INPUT_SIZE and
+        /// DETECT_CONFIDENCE. Returns None when DETECT_MODEL isn't set, so detection stays
+        /// off.
+        pub fn from_env() -> Option<crate::Result<Self>> {
+            let path = env::var("DETECT_MODEL").ok()?;
+            if path.is_empty() {
+                return None;
+            }
+
+            let labels = env::var("DETECT_LABELS")
+                .unwrap_or(String::from(DEFAULT_LABELS))
+                .split(',')
+                .map(|l| l.trim().to_lowercase())
+                .filter(|l| !l.is_empty())
+                .collect();
+            let size = env::var("DETECT_INPUT_SIZE")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(DEFAULT_INPUT_SIZE);
+            let threshold = env::var("DETECT_CONFIDENCE")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(DEFAULT_CONFIDENCE);
+
+            Some(Self::new(&path, labels, size, threshold))
+        }
+    }
+
+    impl Detector for OnnxDetector {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn detect(&self, img: &DynamicImage) -> crate::Result<Vec<Detection>> {
+            let (width, height) = img.dimensions();
+            let letterbox = Letterbox::new(width, height, self.size);
+
+            let size = self.size as usize;
+            let input = Tensor::from_array((
+                [1usize, 3, size, size],
+                letterbox.tensor(img).into_boxed_slice(),
+            ))?;
+            let outputs = self.session.run(ort::inputs![input]?)?;
+
+            // [1, rows, 5 + classes]
+            let output = outputs[0].try_extract_tensor::<f32>()?;
+            let columns = output.shape().last().copied().unwrap_or_default();
+            let Some(data) = output.as_slice() else {
+                return Err(Box::from("detect::detect output tensor isn't contiguous"));
+            };
+            if columns == 0 {
+                return Ok(Vec::new());
+            }
+
+            let detections = decode(
+                data.chunks(columns),
+                &self.labels,
+                self.threshold,
+                &letterbox,
+            );
+            debug!(
+                "detect::detect {} detections above {}",
+                detections.len(),
+                self.threshold
+            );
+
+            Ok(non_max_suppression(detections))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+
+    use crate::cameras::detect::{
+        check_labels, decode, non_max_suppression, BoundingBox, Detection, Detections, Letterbox,
+        EMPTY,
+    };
+
+    #[test]
+    fn letterbox() {
+        // 1920x1080 into 640, scaled by 1/3 and padded top and bottom.
+        let lb = Letterbox::new(1920, 1080, 640);
+        assert_eq!(lb.pad_x, 0.0);
+        assert!((lb.pad_y - 140.0).abs() < 1e-3);
+
+        let b = lb.picture_box(320.0, 320.0, 64.0, 36.0);
+        assert!((b.x - 0.45).abs() < 1e-4);
+        assert!((b.y - 0.45).abs() < 1e-4);
+        assert!((b.width - 0.1).abs() < 1e-4);
+        assert!((b.height - 0.1).abs() < 1e-4);
+
+        let img = DynamicImage::ImageRgb8(RgbImage::new(192, 108));
+        let lb = Letterbox::new(192, 108, 64);
+        let t = lb.tensor(&img);
+        assert_eq!(t.len(), 3 * 64 * 64);
+        // Padding on the first row, the black picture in the middle.
+        assert!(t[0] > 0.4);
+        assert_eq!(t[32 * 64 + 32], 0.0);
+    }
+
+    #[test]
+    fn decode_rows() {
+        let labels = vec![String::from("animal"), String::from("human")];
+        let lb = Letterbox::new(640, 640, 640);
+        let rows: Vec<[f32; 7]> = vec![
+            [100.0, 100.0, 50.0, 50.0, 0.9, 0.9, 0.1],
+            [102.0, 101.0, 50.0, 50.0, 0.8, 0.9, 0.1],
+            [400.0, 400.0, 80.0, 160.0, 0.9, 0.2, 0.8],
+            [500.0, 500.0, 10.0, 10.0, 0.1, 0.5, 0.5],
+        ];
+
+        let detections = decode(rows.iter().map(|r| &r[..]), &labels, 0.2, &lb);
+        assert_eq!(detections.len(), 3);
+
+        let kept = non_max_suppression(detections);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].label, "animal");
+        assert!((kept[0].confidence - 0.81).abs() < 1e-4);
+        assert_eq!(kept[1].label, "human");
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(Detections::new("md_v5a", Vec::new()).labels, vec![EMPTY]);
+
+        let deer = Detection {
+            label: String::from("deer"),
+            confidence: 0.9,
+            bbox: BoundingBox::default(),
+        };
+        let d = Detections::new("species", vec![deer.clone(), deer]);
+        assert_eq!(d.labels, vec!["deer"]);
+        assert_eq!(d.boxes.len(), 2);
+    }
+
+    #[test]
+    fn model_labels() {
+        let labels = |l: &str| l.split(',').map(String::from).collect::<Vec<String>>();
+
+        assert!(check_labels(&labels("animal,human,vehicle")).is_ok());
+        assert!(check_labels(&labels("deer,buck,turkey,hog")).is_ok());
+        assert!(check_labels(&labels("animal,persn,vehicle")).is_err());
+        assert!(check_labels(&labels("deer,empty")).is_err());
+        assert!(check_labels(&[]).is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// Frames with a mean saturation below this are infrared, the IR flash gives grayscale frames.
+const NIGHT_MAX_SATURATION: f32 = 0.04;
+/// Saturations within this factor of NIGHT_MAX_SATURATION are ambiguous, the sun phase decides.
+const AMBIGUOUS_FACTOR: f32 = 1.5;
+/// Pixels sampled along each axis to estimate the saturation of a frame.
+const SATURATION_SAMPLES: u32 = 64;
+/// Share of the darkest and of the brightest pixels clipped by the contrast stretch.
+const STRETCH_CLIP: f32 = 0.005;
+/// Unsharp mask applied to the resized rendition.
+const SHARPEN_SIGMA: f32 = 0.8;
+const SHARPEN_THRESHOLD: i32 = 3;
+
+/// Light a frame was taken in, read from its colors.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Light {
+    #[default]
+    Unknown,
+    Day,
+    /// Grayscale frame taken with the infrared flash.
+    Night,
+}
+
+/// Processing of the derived renditions of a picture, the original is stored untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EnhanceOptions {
+    /// Rotates the rendition upright using the EXIF orientation.
+    pub orient: bool,
+    /// Stretches the contrast of night frames.
+    pub auto_levels: bool,
+    /// Sharpens the rendition after it is resized.
+    pub sharpen: bool,
+}
+
+/// Returns the frame rotated and flipped upright for an EXIF orientation, 1 and unknown values
+/// leave the frame unchanged.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+/// orientation: The EXIF orientation, 1 to 8.
+pub fn orient(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Returns the mean HSV saturation of the frame, 0 to 1, from a grid of sampled pixels.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+pub fn mean_saturation(img: &DynamicImage) -> f32 {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return 0.0;
+    }
+
+    let step_x = (width / SATURATION_SAMPLES).max(1);
+    let step_y = (height / SATURATION_SAMPLES).max(1);
+
+    let mut total = 0.0;
+    let mut count = 0;
+    for y in (0..height).step_by(step_y as usize) {
+        for x in (0..width).step_by(step_x as usize) {
+            let p = img.get_pixel(x, y);
+            let max = p[0].max(p[1]).max(p[2]);
+            let min = p[0].min(p[1]).min(p[2]);
+            if max > 0 {
+                total += (max - min) as f32 / max as f32;
+            }
+            count += 1;
+        }
+    }
+
+    total / count as f32
+}
+
+/// Classifies a frame as day or night by its color, night frames are grayscale.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+pub fn classify(img: &DynamicImage) -> Light {
+    if img.width() == 0 || img.height() == 0 {
+        return Light::Unknown;
+    }
+
+    let saturation = mean_saturation(img);
+    debug!("enhance::classify mean saturation {}", saturation);
+    if saturation < NIGHT_MAX_SATURATION {
+        return Light::Night;
+    }
+
+    Light::Day
+}
+
+/// Classifies a frame as day or night by its color, cross-checked with the sun phase at the
+/// camera when the picture was taken. Frames close to the saturation threshold take the light
+/// of the sun phase. Returns the light and whether it disagrees with the sun phase, e.g. a color
+/// frame at night.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+/// sun_phase: The sun phase at the camera, "day", "dawn", "dusk" or "night", empty when the
+/// camera has no gps fix.
+pub fn classify_with_sun(img: &DynamicImage, sun_phase: &str) -> (Light, bool) {
+    let sun = match sun_phase {
+        "day" => Light::Day,
+        "night" => Light::Night,
+        _ => Light::Unknown,
+    };
+
+    if img.width() == 0 || img.height() == 0 {
+        return (sun, false);
+    }
+
+    let saturation = mean_saturation(img);
+    let ambiguous = saturation > NIGHT_MAX_SATURATION / AMBIGUOUS_FACTOR
+        && saturation < NIGHT_MAX_SATURATION * AMBIGUOUS_FACTOR;
+    if ambiguous && sun != Light::Unknown {
+        debug!(
+            "enhance::classify_with_sun saturation {} is ambiguous, using the sun phase {}",
+            saturation, sun_phase
+        );
+        return (sun, false);
+    }
+
+    let light = if saturation < NIGHT_MAX_SATURATION {
+        Light::Night
+    } else {
+        Light::Day
+    };
+
+    (light, sun != Light::Unknown && sun != light)
+}
+
+/// Stretches the levels of a grayscale frame to the full range. STRETCH_CLIP of the darkest
+/// and of the brightest pixels are clipped, so a few hot pixels don't hold the range.
+///
+/// Arguments:
+///
+/// img: The grayscale picture.
+pub fn stretch_contrast(img: &GrayImage) -> GrayImage {
+    let mut histogram = [0u32; 256];
+    for p in img.pixels() {
+        histogram[p[0] as usize] += 1;
+    }
+
+    let clip = ((img.width() * img.height()) as f32 * STRETCH_CLIP) as u32;
+    let low = clip_level(&histogram, 0..256, clip);
+    let high = clip_level(&histogram, (0..256).rev(), clip);
+
+    let mut out = img.clone();
+    if high <= low {
+        return out;
+    }
+
+    let scale = 255.0 / (high - low) as f32;
+    for p in out.pixels_mut() {
+        let v = (p[0] as f32 - low as f32) * scale;
+        p[0] = v.round().clamp(0.0, 255.0) as u8;
+    }
+
+    out
+}
+
+/// Returns the first level, in the order of levels, past the clipped pixels.
+fn clip_level(histogram: &[u32; 256], levels: impl Iterator<Item = usize>, clip: u32) -> usize {
+    let mut seen = 0;
+    for l in levels {
+        seen += histogram[l];
+        if seen > clip {
+            return l;
+        }
+    }
+
+    0
+}
+
+/// Returns a derived rendition of the frame that fits in width and height, processed per the
+/// options. Night frames are stretched as grayscale.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+/// orientation: The EXIF orientation of the picture.
+/// light: The light the frame was taken in.
+/// width: Maximum width of the rendition.
+/// height: Maximum height of the rendition.
+/// options: Processing done on the rendition.
+pub fn derive(
+    img: &DynamicImage,
+    orientation: u32,
+    light: Light,
+    width: u32,
+    height: u32,
+    options: &EnhanceOptions,
+) -> DynamicImage {
+    // Oriented first, so the rendition fits the box upright.
+    let mut out = if options.orient {
+        orient(img.clone(), orientation)
+    } else {
+        img.clone()
+    };
+    out = out.resize(width, height, FilterType::Triangle);
+
+    if options.auto_levels && light == Light::Night {
+        out = DynamicImage::ImageLuma8(stretch_contrast(&out.to_luma8()));
+    }
+
+    if options.sharpen {
+        out = out.unsharpen(SHARPEN_SIGMA, SHARPEN_THRESHOLD);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+
+    use crate::cameras::enhance::{
+        classify, classify_with_sun, derive, orient, stretch_contrast, EnhanceOptions, Light,
+    };
+
+    #[test]
+    fn orientation() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(40, 30));
+        assert_eq!(orient(img.clone(), 1).dimensions(), (40, 30));
+        assert_eq!(orient(img.clone(), 3).dimensions(), (40, 30));
+        assert_eq!(orient(img.clone(), 6).dimensions(), (30, 40));
+        assert_eq!(orient(img, 8).dimensions(), (30, 40));
+    }
+
+    #[test]
+    fn day_and_night() {
+        let gray = RgbImage::from_fn(64, 48, |x, _| {
+            let v = (x * 3) as u8;
+            Rgb([v, v, v])
+        });
+        assert_eq!(classify(&DynamicImage::ImageRgb8(gray)), Light::Night);
+
+        let green = RgbImage::from_fn(64, 48, |x, _| Rgb([40, 90 + x as u8, 30]));
+        assert_eq!(classify(&DynamicImage::ImageRgb8(green)), Light::Day);
+
+        assert_eq!(classify(&DynamicImage::new_rgb8(0, 0)), Light::Unknown);
+    }
+
+    #[test]
+    fn sun_cross_check() {
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 48, Rgb([90, 90, 90])));
+        let green = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 48, Rgb([40, 120, 30])));
+        // Saturation 0.04, on the threshold.
+        let faint = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 48, Rgb([96, 100, 98])));
+
+        assert_eq!(classify_with_sun(&gray, "night"), (Light::Night, false));
+        assert_eq!(classify_with_sun(&green, "dusk"), (Light::Day, false));
+        assert_eq!(classify_with_sun(&green, "night"), (Light::Day, true));
+        assert_eq!(classify_with_sun(&gray, ""), (Light::Night, false));
+
+        assert_eq!(classify_with_sun(&faint, "night"), (Light::Night, false));
+        assert_eq!(classify_with_sun(&faint, "day"), (Light::Day, false));
+    }
+
+    #[test]
+    fn contrast_stretch() {
+        // A dark, flat IR frame.
+        let img = GrayImage::from_fn(100, 100, |x, _| Luma([20 + (x / 5) as u8]));
+        let out = stretch_contrast(&img);
+
+        let min = out.pixels().map(|p| p[0]).min().unwrap();
+        let max = out.pixels().map(|p| p[0]).max().unwrap();
+        assert_eq!((min, max), (0, 255));
+
+        // A flat frame is left alone.
+        let flat = GrayImage::from_pixel(10, 10, Luma([30]));
+        assert_eq!(stretch_contrast(&flat), flat);
+    }
+
+    #[test]
+    fn derived_rendition() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(800, 600, Rgb([60, 60, 60])));
+        let options = EnhanceOptions {
+            orient: true,
+            auto_levels: true,
+            sharpen: true,
+        };
+
+        let out = derive(&img, 6, Light::Night, 400, 400, &options);
+        assert_eq!(out.dimensions(), (300, 400));
+        assert!(out.as_luma8().is_some());
+    }
+}
@@ -0,0 +1,147 @@
+use std::env;
+use std::fs;
+
+use log::{debug, error, info};
+
+use crate::cameras::GPS;
+
+/// A camera further than this from every zip centroid gets no zip.
+pub const ZIP_MAX_MILES: f64 = 25.0;
+
+/// Zip code centroids, loaded from a CSV of zip,latitude,longitude or from the census ZCTA
+/// gazetteer (e.g. 2020_Gaz_zcta_national.txt), whose GEOID, INTPTLAT and INTPTLONG columns
+/// are used.
+#[derive(Debug, Clone, Default)]
+pub struct ZipCentroids {
+    zips: Vec<(String, f64, f64)>,
+}
+
+impl ZipCentroids {
+    /// Parses the centroids, the columns are found by the header and the rows that don't parse
+    /// are skipped.
+    ///
+    /// Arguments:
+    ///
+    /// text: The CSV or the tab separated gazetteer.
+    pub fn parse(text: &str) -> Self {
+        let mut lines = text.lines();
+        let Some(header) = lines.next() else {
+            return ZipCentroids::default();
+        };
+
+        let separator = if header.contains('\t') { '\t' } else { ',' };
+        let columns: Vec<String> = header
+            .split(separator)
+            .map(|c| c.trim().to_lowercase())
+            .collect();
+        let column = |names: [&str; 2]| columns.iter().position(|c| names.contains(&c.as_str()));
+        let (Some(zip), Some(lat), Some(lng)) = (
+            column(["zip", "geoid"]),
+            column(["latitude", "intptlat"]),
+            column(["longitude", "intptlong"]),
+        ) else {
+            return ZipCentroids::default();
+        };
+
+        let zips = lines
+            .filter_map(|line| {
+                let cols: Vec<&str> = line.split(separator).map(str::trim).collect();
+                let lat = cols.get(lat)?.parse().ok()?;
+                let lng = cols.get(lng)?.parse().ok()?;
+                Some((cols.get(zip)?.to_string(), lat, lng))
+            })
+            .collect();
+
+        ZipCentroids { zips }
+    }
+
+    /// Loads the centroids from the file at ZIP_CENTROIDS_FILE, None when it isn't set or the
+    /// file can't be read.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("ZIP_CENTROIDS_FILE").ok()?;
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let centroids = ZipCentroids::parse(&text);
+                info!(
+                    "geocode::from_env loaded {} zip centroids from {}",
+                    centroids.zips.len(),
+                    path
+                );
+                Some(centroids)
+            }
+            Err(e) => {
+                error!("geocode::from_env unable to read {}, {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Returns the zip code with the centroid nearest to the camera, None when no centroid is
+    /// within ZIP_MAX_MILES.
+    ///
+    /// Arguments:
+    ///
+    /// gps: The location of the camera.
+    pub fn nearest(&self, gps: &GPS) -> Option<&str> {
+        let (zip, miles) = self
+            .zips
+            .iter()
+            .map(|(zip, lat, lng)| (zip, gps.distance_miles(*lat, *lng)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        if miles > ZIP_MAX_MILES {
+            debug!(
+                "geocode::nearest nearest zip {} is {:.1} miles away",
+                zip, miles
+            );
+            return None;
+        }
+
+        Some(zip.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cameras::geocode::ZipCentroids;
+    use crate::cameras::{tests, GPS};
+
+    #[test]
+    fn zip() {
+        let centroids = ZipCentroids::parse(
+            "zip,latitude,longitude\n33030,25.4766,-80.4830\n33170,25.5584,-80.4501\nbad,,\n",
+        );
+
+        assert_eq!(
+            centroids.nearest(&GPS::new(25.544241, -80.439992)),
+            Some("33170")
+        );
+        assert_eq!(centroids.nearest(&GPS::new(44.9778, -93.2650)), None);
+        assert_eq!(
+            ZipCentroids::default().nearest(&GPS::new(25.5, -80.4)),
+            None
+        );
+
+        // The zip is set from the gps fix, a zip already set is kept.
+        let mut camera = tests::camera();
+        camera.apply_zip(&centroids);
+        assert_eq!(camera.zip, "33170");
+        camera.zip = String::from("33031");
+        camera.apply_zip(&centroids);
+        assert_eq!(camera.zip, "33031");
+    }
+
+    #[test]
+    fn gazetteer() {
+        let centroids = ZipCentroids::parse(
+            "GEOID\tALAND\tAWATER\tALAND_SQMI\tAWATER_SQMI\tINTPTLAT\tINTPTLONG  \n\
+             55401\t3637893\t632643\t1.405\t0.244\t44.984731\t-93.270261  \n\
+             55402\t393983\t0\t0.152\t0.000\t44.975905\t-93.271468  \n",
+        );
+
+        assert_eq!(
+            centroids.nearest(&GPS::new(44.9778, -93.2650)),
+            Some("55402")
+        );
+    }
+}
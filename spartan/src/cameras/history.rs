@@ -0,0 +1,336 @@
+use chrono::{Duration, Utc};
+use log::debug;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{TimeseriesGranularity, TimeseriesOptions};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::cameras::Camera;
+
+const COLLECTION: &str = "camera_status";
+
+/// A battery level increase of at least this many points means the batteries were replaced.
+const BATTERY_CHANGE_POINTS: f64 = 10.0;
+/// A voltage increase of at least this many volts means the batteries were replaced. Cameras
+/// report the level in coarse steps, the voltage shows a replacement the level may miss.
+const BATTERY_CHANGE_VOLTS: f64 = 0.5;
+
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// Status of a camera at a point in time, stored in the camera_status time series.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatusSnapshot {
+    pub timestamp: DateTime,
+    pub camera_id: String,
+    pub battery_level: i64,
+    pub signal: i64,
+    pub temperature: f64,
+    /// Used memory in MB.
+    pub memory: f64,
+    /// Memory size in MB.
+    pub memory_limit: f64,
+    /// Voltage of the active power source in volts, 0 when not reported.
+    #[serde(default)]
+    pub voltage: f64,
+}
+
+impl From<&Camera> for StatusSnapshot {
+    fn from(value: &Camera) -> Self {
+        StatusSnapshot {
+            timestamp: value.status.last_transmission,
+            camera_id: value.camera_id.clone(),
+            battery_level: value.status.battery_level,
+            signal: value.status.signal,
+            temperature: value.status.temperature,
+            memory: value.status.memory,
+            memory_limit: value.status.memory_limit,
+            voltage: value.status.voltage,
+        }
+    }
+}
+
+impl StatusSnapshot {
+    /// Creates the camera_status time series collection if it doesn't exist yet.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    pub async fn create_collection(db: &Database) -> crate::Result<()> {
+        let names = db.list_collection_names().await?;
+        if names.iter().any(|n| n == COLLECTION) {
+            return Ok(());
+        }
+
+        let options = TimeseriesOptions::builder()
+            .time_field(String::from("timestamp"))
+            .meta_field(Some(String::from("camera_id")))
+            .granularity(Some(TimeseriesGranularity::Hours))
+            .build();
+
+        db.create_collection(COLLECTION).timeseries(options).await?;
+        Ok(())
+    }
+
+    /// Saves the snapshot. A camera reports the same status until its next transmission, so a
+    /// snapshot is only written once per transmission.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    pub async fn save(&self, db: &Database) -> crate::Result<()> {
+        let coll: Collection<StatusSnapshot> = db.collection(COLLECTION);
+        let filter = doc! {
+            "camera_id": &self.camera_id,
+            "timestamp": self.timestamp,
+        };
+
+        if coll.find_one(filter).await?.is_some() {
+            debug!(
+                "history::save snapshot exists, camera_id: {}",
+                self.camera_id
+            );
+            return Ok(());
+        }
+
+        coll.insert_one(self).await?;
+        Ok(())
+    }
+}
+
+/// The status snapshots of a camera, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct StatusHistory {
+    pub camera_id: String,
+    pub snapshots: Vec<StatusSnapshot>,
+}
+
+impl StatusHistory {
+    /// Loads the status snapshots of a camera for the last days.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// camera_id: The id of the camera.
+    /// days: Number of days of history to load.
+    pub async fn load(db: &Database, camera_id: &str, days: i64) -> crate::Result<Self> {
+        let coll: Collection<StatusSnapshot> = db.collection(COLLECTION);
+        let since = DateTime::from_chrono(Utc::now() - Duration::days(days));
+        let filter = doc! {
+            "camera_id": camera_id,
+            "timestamp": { "$gte": since },
+        };
+
+        let mut cursor = coll.find(filter).sort(doc! { "timestamp": 1 }).await?;
+        let mut snapshots = Vec::new();
+        while cursor.advance().await? {
+            snapshots.push(cursor.deserialize_current()?);
+        }
+
+        Ok(StatusHistory {
+            camera_id: camera_id.to_string(),
+            snapshots,
+        })
+    }
+
+    /// Returns the battery percentage used per day, since the batteries were last replaced.
+    /// None when there aren't enough readings.
+    pub fn battery_drain_rate(&self) -> Option<f64> {
+        let readings = self.battery_readings();
+        slope_per_day(&readings).map(|s| -s)
+    }
+
+    /// Returns the estimated date the batteries run out, by fitting a linear drain to the
+    /// battery readings since the batteries were last replaced. None when there aren't enough
+    /// readings or the battery isn't draining.
+    pub fn estimated_empty_date(&self) -> Option<DateTime> {
+        let readings = self.battery_readings();
+        let rate = slope_per_day(&readings)?;
+        if rate >= 0.0 {
+            return None;
+        }
+
+        let (last, level) = *readings.last()?;
+        let days = level / -rate;
+        Some(DateTime::from_millis(
+            last.timestamp_millis() + (days * MILLIS_PER_DAY) as i64,
+        ))
+    }
+
+    /// Returns the change of signal bars per day, negative when the signal is getting weaker.
+    pub fn signal_trend(&self) -> Option<f64> {
+        let points: Vec<(DateTime, f64)> = self
+            .snapshots
+            .iter()
+            .map(|s| (s.timestamp, s.signal as f64))
+            .collect();
+
+        slope_per_day(&points)
+    }
+
+    /// Returns the MB written to the SD card per day.
+    pub fn sd_fill_rate(&self) -> Option<f64> {
+        let points: Vec<(DateTime, f64)> = self
+            .snapshots
+            .iter()
+            .map(|s| (s.timestamp, s.memory))
+            .collect();
+
+        slope_per_day(&points)
+    }
+
+    /// Returns the battery readings since the batteries were last replaced.
+    pub fn battery_readings(&self) -> Vec<(DateTime, f64)> {
+        let mut start = 0;
+        for (i, pair) in self.snapshots.windows(2).enumerate() {
+            let level = (pair[1].battery_level - pair[0].battery_level) as f64;
+            let volts = if pair[0].voltage > 0.0 && pair[1].voltage > 0.0 {
+                pair[1].voltage - pair[0].voltage
+            } else {
+                0.0
+            };
+
+            if level >= BATTERY_CHANGE_POINTS || volts >= BATTERY_CHANGE_VOLTS {
+                start = i + 1;
+            }
+        }
+
+        self.snapshots[start..]
+            .iter()
+            .map(|s| (s.timestamp, s.battery_level as f64))
+            .collect()
+    }
+}
+
+/// Returns the least squares slope of the values per day. None with less than two readings or
+/// when all readings were taken at the same time.
+pub fn slope_per_day(points: &[(DateTime, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let t0 = points[0].0.timestamp_millis();
+    let xs: Vec<f64> = points
+        .iter()
+        .map(|p| (p.0.timestamp_millis() - t0) as f64 / MILLIS_PER_DAY)
+        .collect();
+
+    let n = points.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+
+    let mut num = 0.0;
+    let mut den = 0.0;
+    for (x, p) in xs.iter().zip(points) {
+        num += (x - mean_x) * (p.1 - mean_y);
+        den += (x - mean_x).powi(2);
+    }
+
+    if den == 0.0 {
+        return None;
+    }
+
+    Some(num / den)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::DateTime;
+
+    use crate::cameras::history::{StatusHistory, StatusSnapshot};
+
+    fn snapshot(day: i64, battery: i64, signal: i64, memory: f64) -> StatusSnapshot {
+        StatusSnapshot {
+            timestamp: DateTime::from_millis(1_720_000_000_000 + day * 86_400_000),
+            camera_id: String::from("66985496c6eb10dbad5c51f6"),
+            battery_level: battery,
+            signal,
+            temperature: 70.0,
+            memory,
+            memory_limit: 29798.0,
+            voltage: 0.0,
+        }
+    }
+
+    #[test]
+    fn trends() {
+        let history = StatusHistory {
+            camera_id: String::from("66985496c6eb10dbad5c51f6"),
+            snapshots: vec![
+                snapshot(0, 100, 5, 10.0),
+                snapshot(1, 98, 5, 30.0),
+                snapshot(2, 96, 4, 50.0),
+                snapshot(3, 94, 3, 70.0),
+            ],
+        };
+
+        assert!((history.battery_drain_rate().unwrap() - 2.0).abs() < 1e-9);
+        assert!((history.sd_fill_rate().unwrap() - 20.0).abs() < 1e-9);
+        assert!(history.signal_trend().unwrap() < 0.0);
+    }
+
+    #[test]
+    fn battery_replaced() {
+        let history = StatusHistory {
+            camera_id: String::from("66985496c6eb10dbad5c51f6"),
+            snapshots: vec![
+                snapshot(0, 20, 5, 10.0),
+                snapshot(1, 10, 5, 10.0),
+                snapshot(2, 100, 5, 10.0),
+                snapshot(3, 99, 5, 10.0),
+                snapshot(4, 98, 5, 10.0),
+            ],
+        };
+
+        assert_eq!(history.battery_readings().len(), 3);
+        assert!((history.battery_drain_rate().unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_date() {
+        let history = StatusHistory {
+            camera_id: String::from("66985496c6eb10dbad5c51f6"),
+            snapshots: vec![
+                snapshot(0, 60, 5, 10.0),
+                snapshot(1, 58, 5, 10.0),
+                snapshot(2, 56, 5, 10.0),
+                snapshot(3, 54, 5, 10.0),
+            ],
+        };
+
+        // 54% left at 2% a day.
+        let date = history.estimated_empty_date().expect("empty date");
+        assert_eq!(date, snapshot(30, 0, 0, 0.0).timestamp);
+    }
+
+    #[test]
+    fn battery_replaced_by_voltage() {
+        let mut snapshots = vec![
+            snapshot(0, 80, 5, 10.0),
+            snapshot(1, 80, 5, 10.0),
+            snapshot(2, 80, 5, 10.0),
+            snapshot(3, 79, 5, 10.0),
+        ];
+        for (s, v) in snapshots.iter_mut().zip([11.2, 11.0, 12.4, 12.3]) {
+            s.voltage = v;
+        }
+
+        let history = StatusHistory {
+            camera_id: String::from("66985496c6eb10dbad5c51f6"),
+            snapshots,
+        };
+
+        assert_eq!(history.battery_readings().len(), 2);
+    }
+
+    #[test]
+    fn not_enough_readings() {
+        let history = StatusHistory {
+            camera_id: String::from("66985496c6eb10dbad5c51f6"),
+            snapshots: vec![snapshot(0, 100, 5, 10.0)],
+        };
+
+        assert!(history.battery_drain_rate().is_none());
+        assert!(history.signal_trend().is_none());
+        assert!(history.estimated_empty_date().is_none());
+    }
+}
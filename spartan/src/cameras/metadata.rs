@@ -0,0 +1,213 @@
+use std::io::Cursor;
+
+use chrono::{FixedOffset, NaiveDate, TimeZone, Utc};
+use exif::{Field, In, Reader, Tag, Value};
+use log::debug;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// EXIF metadata read from a downloaded picture.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Exif {
+    pub make: String,
+    pub model: String,
+    pub software: String,
+    pub capture_time: String,
+    pub orientation: u32,
+    pub width: u32,
+    pub height: u32,
+    pub iso: u32,
+    pub exposure_time: f64,
+    pub flash: bool,
+    pub temperature: Option<f64>,
+    pub moon_phase: String,
+    pub description: String,
+}
+
+impl Exif {
+    /// Returns the capture time of the picture as a UTC date. When the camera did not write a
+    /// time offset the capture time is assumed to be UTC.
+    pub fn capture_date(&self) -> Option<DateTime> {
+        parse_capture_time(&self.capture_time)
+    }
+}
+
+/// Reads the EXIF metadata from the bytes of a jpeg image. Returns None when the image has no
+/// EXIF block or it can't be parsed.
+pub fn read_exif(bytes: &[u8]) -> Option<Exif> {
+    let mut cursor = Cursor::new(bytes);
+    let data = match Reader::new().read_from_container(&mut cursor) {
+        Ok(d) => d,
+        Err(e) => {
+            debug!("metadata::read_exif, no exif data found, {:?}", e);
+            return None;
+        }
+    };
+
+    let ascii = |tag: Tag| -> String {
+        data.get_field(tag, In::PRIMARY)
+            .map(field_ascii)
+            .unwrap_or_default()
+    };
+
+    let uint = |tag: Tag| -> u32 {
+        data.get_field(tag, In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .unwrap_or(0)
+    };
+
+    // DateTimeOriginal is the shutter time, DateTime is only the last modification.
+    let mut capture_time = ascii(Tag::DateTimeOriginal);
+    if capture_time.is_empty() {
+        capture_time = ascii(Tag::DateTime);
+    }
+
+    let offset = ascii(Tag::OffsetTimeOriginal);
+    if !capture_time.is_empty() && !offset.is_empty() {
+        capture_time = format!("{} {}", capture_time, offset);
+    }
+
+    let exposure_time = match data.get_field(Tag::ExposureTime, In::PRIMARY) {
+        Some(Field {
+            value: Value::Rational(r),
+            ..
+        }) if !r.is_empty() => r[0].to_f64(),
+        _ => 0.0,
+    };
+
+    let temperature = match data.get_field(Tag::Temperature, In::PRIMARY) {
+        Some(Field {
+            value: Value::SRational(r),
+            ..
+        }) if !r.is_empty() => Some(r[0].to_f64()),
+        _ => None,
+    };
+
+    let mut width = uint(Tag::PixelXDimension);
+    if width == 0 {
+        width = uint(Tag::ImageWidth);
+    }
+
+    let mut height = uint(Tag::PixelYDimension);
+    if height == 0 {
+        height = uint(Tag::ImageLength);
+    }
+
+    let description = ascii(Tag::ImageDescription);
+
+    Some(Exif {
+        make: ascii(Tag::Make),
+        model: ascii(Tag::Model),
+        software: ascii(Tag::Software),
+        capture_time,
+        orientation: uint(Tag::Orientation),
+        width,
+        height,
+        iso: uint(Tag::PhotographicSensitivity),
+        exposure_time,
+        // Bit 0 of the flash tag is set when the flash (IR on trail cameras) fired.
+        flash: uint(Tag::Flash) & 1 == 1,
+        temperature,
+        moon_phase: moon_phase_from_description(&description),
+        description,
+    })
+}
+
+/// Parses an EXIF date ("2024:07:17 19:51:41", optionally followed by an offset "-04:00").
+pub fn parse_capture_time(value: &str) -> Option<DateTime> {
+    let mut parts = value.split_whitespace();
+    let date = parts.next()?;
+    let time = parts.next()?;
+    let offset = parts.next();
+
+    let d: Vec<u32> = date.split(':').filter_map(|x| x.parse().ok()).collect();
+    let t: Vec<u32> = time.split(':').filter_map(|x| x.parse().ok()).collect();
+    if d.len() != 3 || t.len() != 3 {
+        return None;
+    }
+
+    let naive = NaiveDate::from_ymd_opt(d[0] as i32, d[1], d[2])?.and_hms_opt(t[0], t[1], t[2])?;
+
+    let utc = match offset.and_then(parse_offset) {
+        Some(o) => o.from_local_datetime(&naive).single()?.with_timezone(&Utc),
+        None => Utc.from_utc_datetime(&naive),
+    };
+
+    Some(DateTime::from_chrono(utc))
+}
+
+fn parse_offset(value: &str) -> Option<FixedOffset> {
+    let sign = match value.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+
+    let mut parts = value[1..].split(':');
+    let hours: i32 = parts.next()?.parse().ok()?;
+    let minutes: i32 = parts.next().unwrap_or("0").parse().ok()?;
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+fn field_ascii(field: &Field) -> String {
+    match field.value {
+        Value::Ascii(ref v) if !v.is_empty() => String::from_utf8_lossy(&v[0])
+            .trim_matches(char::from(0))
+            .trim()
+            .to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Some Spypoint firmwares write the moon phase into the image description.
+fn moon_phase_from_description(description: &str) -> String {
+    let lower = description.to_lowercase();
+    for phase in [
+        "new moon",
+        "waxing crescent",
+        "first quarter",
+        "waxing gibbous",
+        "full moon",
+        "waning gibbous",
+        "last quarter",
+        "waning crescent",
+    ] {
+        if lower.contains(phase) {
+            return phase.to_string();
+        }
+    }
+
+    String::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cameras::metadata::{parse_capture_time, read_exif};
+    use crate::cameras::pictures::basic_thumbnail;
+
+    #[test]
+    fn capture_time() {
+        let date = parse_capture_time("2024:07:17 19:51:41").expect("capture date");
+        assert_eq!(
+            date.try_to_rfc3339_string().unwrap(),
+            "2024-07-17T19:51:41Z"
+        );
+
+        let date = parse_capture_time("2024:07:17 19:51:41 -04:00").expect("capture date");
+        assert_eq!(
+            date.try_to_rfc3339_string().unwrap(),
+            "2024-07-17T23:51:41Z"
+        );
+
+        assert!(parse_capture_time("").is_none());
+        assert!(parse_capture_time("2024-07-17").is_none());
+    }
+
+    #[test]
+    fn no_exif() {
+        let bytes = basic_thumbnail(10, 10).expect("Black Thumbnail");
+        assert!(read_exif(&bytes).is_none());
+    }
+}
@@ -1,39 +1,396 @@
 use bson::DateTime;
 use log::debug;
-use mongodb::{bson, Collection, Database};
-use mongodb::bson::doc;
-use serde::{Deserialize, Serialize};
+use mongodb::{bson, Collection, Database, IndexModel};
+use mongodb::bson::{doc, Document};
+use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::cameras::carrier::carrier_name;
+use crate::cameras::geocode::ZipCentroids;
+use crate::cameras::quota::QuotaAdjustment;
 use crate::spypoint;
 
+pub mod alerts;
+pub mod astro;
+pub mod banner;
+pub mod carrier;
+pub mod detect;
+pub mod enhance;
+pub mod geocode;
+pub mod history;
+pub mod metadata;
+pub mod paths;
 pub mod pictures;
+pub mod quota;
+pub mod storage;
 
 const COLLECTION: &str = "cameras";
 
+/// A field of a camera owned by the web app.
+pub struct UserField {
+    /// Name of the field in the stored document.
+    pub name: &'static str,
+    /// Whether the sync fills the field in for stored cameras where it's empty, e.g. the zip
+    /// found from the gps fix.
+    pub fill_empty: bool,
+    get: fn(&Camera) -> &str,
+    set: fn(&mut Camera, String),
+}
+
+/// Fields of a camera owned by the web app. A sync only sets them when the camera is first
+/// stored, every other field is owned by the provider and updated on each sync.
+pub const USER_FIELDS: [UserField; 4] = [
+    UserField {
+        name: "location",
+        fill_empty: false,
+        get: |c| &c.location,
+        set: |c, v| c.location = v,
+    },
+    UserField {
+        name: "zip",
+        fill_empty: true,
+        get: |c| &c.zip,
+        set: |c, v| c.zip = v,
+    },
+    UserField {
+        name: "updated_by",
+        fill_empty: false,
+        get: |c| &c.updated_by,
+        set: |c, v| c.updated_by = v,
+    },
+    UserField {
+        name: "notes",
+        fill_empty: false,
+        get: |c| &c.notes,
+        set: |c, v| c.notes = v,
+    },
+];
+
+/// A camera moved further than this is at a new location.
+pub const RELOCATION_MILES: f64 = 0.25;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Status {
-    last_transmission_timestamp: i64,
+    /// Unix time in seconds of the last transmission.
+    pub last_transmission_timestamp: i64,
     #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
-    last_transmission: DateTime,
-    memory: f64,
-    temperature: f64,
-    memory_limit: f64,
-    signal: i64,
-    battery_level: i64,
+    pub last_transmission: DateTime,
+    pub memory: f64,
+    pub temperature: f64,
+    pub memory_limit: f64,
+    pub signal: i64,
+    pub battery_level: i64,
+    /// Voltage of the active power source in volts.
+    #[serde(default)]
+    pub voltage: f64,
+    #[serde(default)]
+    pub battery_type: String,
+    /// Index of the active source in power_sources.
+    #[serde(default)]
+    pub active_power_source: i64,
+    #[serde(default)]
+    pub power_sources: Vec<PowerSource>,
+    #[serde(default, deserialize_with = "spypoint::deserialize_notifications")]
+    pub notifications: Vec<spypoint::Notification>,
+    #[serde(default)]
+    pub cell: Cell,
+    #[serde(default)]
+    pub capability: Capability,
+    #[serde(default)]
+    pub install_date: String,
+}
+
+/// A battery tray or external power source of a camera.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PowerSource {
+    pub location: String,
+    pub r#type: String,
+    pub percentage: i64,
+    /// Voltage in volts.
+    pub voltage: f64,
+}
+
+impl From<&spypoint::PowerSource> for PowerSource {
+    fn from(value: &spypoint::PowerSource) -> Self {
+        PowerSource {
+            location: value.location.clone(),
+            r#type: value.type_field.clone(),
+            percentage: value.percentage,
+            voltage: value.voltage as f64 / 1000.0,
+        }
+    }
+}
+
+/// The cellular connection of a camera.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Cell {
+    pub dbm: i64,
+    /// Mobile country code.
+    pub mcc: i64,
+    /// Mobile network code.
+    pub mnc: i64,
+    /// Network type, e.g. "LTE".
+    pub network_type: String,
+    pub signal_percentage: i64,
+    pub low_signal: bool,
+}
+
+/// Features supported by a camera.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Capability {
+    pub hd_request: bool,
+    pub video: bool,
+    pub survival_mode: bool,
+}
+
+/// Mean radius of the earth in miles.
+pub const EARTH_RADIUS_MILES: f64 = 3958.8;
+
+/// A GeoJSON point, coordinates are [longitude, latitude].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Point {
+    pub r#type: String,
+    pub coordinates: [f64; 2],
+}
+
+impl Point {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Point {
+            r#type: String::from("Point"),
+            coordinates: [longitude, latitude],
+        }
+    }
 }
 
+/// Location of a camera. The location is stored as a GeoJSON point so cameras can be queried
+/// with the 2dsphere index.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GPS {
+    #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub last_updated_timestamp: DateTime,
+    pub location: Point,
+    pub geo_hash: String,
+    /// Latitude as reported by the camera, e.g. "N25 32.654460".
+    pub latitude_dms: String,
+    /// Longitude as reported by the camera, e.g. "W80 26.399520".
+    pub longitude_dms: String,
+}
+
+impl GPS {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        GPS {
+            last_updated_timestamp: DateTime::now(),
+            location: Point::new(latitude, longitude),
+            geo_hash: String::new(),
+            latitude_dms: String::new(),
+            longitude_dms: String::new(),
+        }
+    }
+
+    pub fn latitude(&self) -> f64 {
+        self.location.coordinates[1]
+    }
+
+    pub fn longitude(&self) -> f64 {
+        self.location.coordinates[0]
+    }
+
+    /// Returns the great-circle distance in miles between the camera and a point.
+    ///
+    /// Arguments:
+    ///
+    /// latitude: Latitude of the point in degrees.
+    /// longitude: Longitude of the point in degrees.
+    pub fn distance_miles(&self, latitude: f64, longitude: f64) -> f64 {
+        let (lat1, lat2) = (self.latitude().to_radians(), latitude.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lng = (longitude - self.longitude()).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_MILES * a.sqrt().asin()
+    }
+
+    /// Converts a coordinate reported by a Spypoint camera, returns None when the camera has
+    /// no fix.
+    pub fn from_coordinate(value: &spypoint::Coordinate) -> Option<GPS> {
+        if value.position.coordinates.len() != 2 {
+            return None;
+        }
+
+        let lng = value.position.coordinates[0];
+        let lat = value.position.coordinates[1];
+
+        // A camera without a fix reports 0,0.
+        if lat == 0.0 && lng == 0.0 {
+            return None;
+        }
+
+        Some(GPS {
+            last_updated_timestamp: DateTime::parse_rfc3339_str(&value.date_time)
+                .unwrap_or(DateTime::now()),
+            location: Point::new(lat, lng),
+            geo_hash: value.geo_hash.clone(),
+            latitude_dms: value.latitude.clone(),
+            longitude_dms: value.longitude.clone(),
+        })
+    }
+}
+
+/// The gps of camera documents stored before the location was a GeoJSON point, the
+/// coordinates are decimal strings and a camera without a fix has 0,0.
+#[derive(Deserialize)]
+struct LegacyGPS {
     #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
     last_updated_timestamp: DateTime,
     longitude: String,
     latitude: String,
 }
 
+impl LegacyGPS {
+    fn to_gps(&self) -> Option<GPS> {
+        let lat: f64 = self.latitude.trim().parse().ok()?;
+        let lng: f64 = self.longitude.trim().parse().ok()?;
+        if lat == 0.0 && lng == 0.0 {
+            return None;
+        }
+
+        let mut gps = GPS::new(lat, lng);
+        gps.last_updated_timestamp = self.last_updated_timestamp;
+        Some(gps)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredGPS {
+    Point(GPS),
+    Legacy(LegacyGPS),
+}
+
+/// Reads the gps of a stored camera in the current or the legacy shape, the legacy shape is
+/// rewritten the next time the camera is saved.
+fn deserialize_gps<'de, D>(deserializer: D) -> Result<Option<GPS>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let gps = match Option::<StoredGPS>::deserialize(deserializer)? {
+        Some(StoredGPS::Point(gps)) => Some(gps),
+        Some(StoredGPS::Legacy(legacy)) => legacy.to_gps(),
+        None => None,
+    };
+
+    Ok(gps)
+}
+
+/// The subscription of a camera and its plan.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Subscription {
+    pub plan_id: String,
+    pub plan_name: String,
+    pub payment_status: String,
+    pub is_active: bool,
+    pub is_free: bool,
+    pub currency: String,
+    pub payment_frequency: String,
+    /// Photos transmitted in the current billing cycle.
+    pub photo_count: i64,
+    /// Photos included per month, 0 when unlimited.
+    pub photo_count_per_month: i64,
+    pub price_per_month: i64,
+    pub price_per_year: i64,
+    pub start_date_billing_cycle: Option<DateTime>,
+    pub end_date_billing_cycle: Option<DateTime>,
+    pub month_end_billing_cycle: Option<DateTime>,
+    pub is_auto_renew: bool,
+    pub is_upgradable: bool,
+}
+
+impl From<&spypoint::Subscription> for Subscription {
+    fn from(value: &spypoint::Subscription) -> Self {
+        let date = |s: &str| DateTime::parse_rfc3339_str(s).ok();
+
+        Subscription {
+            plan_id: value.plan.id.clone(),
+            plan_name: value.plan.name.clone(),
+            payment_status: value.payment_status.clone(),
+            is_active: value.is_active,
+            is_free: value.is_free,
+            currency: value.currency.clone(),
+            payment_frequency: value.payment_frequency.clone(),
+            photo_count: value.photo_count,
+            photo_count_per_month: value.plan.photo_count_per_month,
+            price_per_month: value.plan.price_per_month_if_paid_per_month,
+            price_per_year: value.plan.price_per_year,
+            start_date_billing_cycle: date(&value.start_date_billing_cycle),
+            end_date_billing_cycle: date(&value.end_date_billing_cycle),
+            month_end_billing_cycle: date(&value.month_end_billing_cycle),
+            is_auto_renew: value.is_auto_renew,
+            is_upgradable: value.plan.is_upgradable,
+        }
+    }
+}
+
+impl Subscription {
+    /// Returns the percentage of the monthly photo quota used, None when the plan is unlimited.
+    pub fn quota_used(&self) -> Option<f64> {
+        if self.photo_count_per_month <= 0 {
+            return None;
+        }
+
+        Some(self.photo_count as f64 / self.photo_count_per_month as f64 * 100.0)
+    }
+
+    /// Returns the whole days left in the billing cycle, None when the end date is unknown.
+    ///
+    /// Arguments:
+    ///
+    /// now: The current time.
+    pub fn days_left(&self, now: DateTime) -> Option<i64> {
+        let end = self.end_date_billing_cycle?;
+        Some((end.timestamp_millis() - now.timestamp_millis()) / 86_400_000)
+    }
+}
+
+/// Standard SD card sizes in GB.
+const SD_CARD_SIZES: [i64; 9] = [2, 4, 8, 16, 32, 64, 128, 256, 512];
+
+/// Returns the label of the SD card size, e.g. "32 GB", from the memory size in MB the camera
+/// reports. The formatted size is a bit below the size printed on the card. Empty when no card
+/// is reported.
+pub fn sd_card_label(size_mb: i64) -> String {
+    if size_mb <= 0 {
+        return String::new();
+    }
+
+    let gb = SD_CARD_SIZES
+        .iter()
+        .find(|gb| size_mb <= *gb * 1024)
+        .copied()
+        .unwrap_or((size_mb + 1023) / 1024);
+
+    format!("{} GB", gb)
+}
+
+/// A name and place a camera had for a period of time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LocationPeriod {
+    pub name: String,
+    /// None when the camera had no gps fix.
+    pub gps: Option<Point>,
+    pub start: DateTime,
+    /// None for the current location.
+    pub end: Option<DateTime>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Usage {
-    stored_photos: i64,
-    photos: i64,
+    /// Pictures of the camera stored in the database.
+    pub stored_photos: i64,
+    /// Photos transmitted in the current billing cycle.
+    pub photos: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -49,6 +406,7 @@ pub struct Camera {
     pub registration_status: String,
     pub created_timestamp: String,
     pub usage: Usage,
+    /// Spypoint doesn't report a status file, it's kept for the documents of other cameras.
     pub status_file: String,
     pub phone_carrier: String,
     pub account_id: String,
@@ -60,8 +418,25 @@ pub struct Camera {
     pub status: Status,
     pub photo_count: i64,
     pub sd_card: String,
-    pub gps: GPS,
+    /// None when the camera has no gps fix.
+    #[serde(default, deserialize_with = "deserialize_gps")]
+    pub gps: Option<GPS>,
     pub zip: String,
+    /// Estimated date the batteries run out, None when the history doesn't show a drain yet.
+    #[serde(default)]
+    pub battery_empty_date: Option<DateTime>,
+    /// None when the camera has no subscription.
+    #[serde(default)]
+    pub subscription: Option<Subscription>,
+    #[serde(default)]
+    pub notes: String,
+    /// Names and places of the camera, oldest first.
+    #[serde(default)]
+    pub location_history: Vec<LocationPeriod>,
+    /// The last config change sent to keep the camera within its quota, only written by
+    /// save_quota_adjustment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_adjustment: Option<QuotaAdjustment>,
 }
 
 impl From<spypoint::Camera> for Camera {
@@ -71,12 +446,11 @@ impl From<spypoint::Camera> for Camera {
             .unwrap_or(bson::DateTime::now());
 
         // Subscription
-        let subscription = value.clone().subscriptions.into_iter().next();
+        let subscription = value.subscriptions.first().map(Subscription::from);
         let mut reg_status = String::from("");
         let mut photo_count = 0;
-        if subscription.is_some() {
-            let s = subscription.unwrap();
-            reg_status = s.payment_status;
+        if let Some(s) = &subscription {
+            reg_status = s.payment_status.clone();
             photo_count = s.photo_count;
         }
 
@@ -89,34 +463,67 @@ impl From<spypoint::Camera> for Camera {
             .next()
             .unwrap_or(0);
 
+        let power_sources: Vec<PowerSource> = value
+            .status
+            .power_sources
+            .iter()
+            .map(PowerSource::from)
+            .collect();
+
+        // Voltage of the active power source.
+        let voltage = usize::try_from(value.status.active_power_source)
+            .ok()
+            .and_then(|i| power_sources.get(i))
+            .map(|p| p.voltage)
+            .unwrap_or_default();
+
+        let signal = &value.status.signal;
+        let cell = Cell {
+            dbm: signal.d_bm,
+            mcc: signal.mcc,
+            mnc: signal.mnc,
+            network_type: signal.signal_type.clone(),
+            signal_percentage: signal.processed.percentage,
+            low_signal: signal.processed.low_signal,
+        };
+
+        let capability = Capability {
+            hd_request: value.status.capability.hd_request,
+            video: value.status.capability.video,
+            survival_mode: value.status.capability.survival_mode,
+        };
+
+        let phone_carrier = carrier_name(signal.mcc, signal.mnc)
+            .map(String::from)
+            .unwrap_or_default();
+
         let status = Status {
-            last_transmission_timestamp: 0, //Redo
+            last_transmission_timestamp: last_update.timestamp_millis() / 1000,
             last_transmission: last_update,
             memory: value.status.memory.used as f64,
             temperature: value.status.temperature.value as f64,
             memory_limit: value.status.memory.size as f64,
             signal: value.status.signal.processed.bar,
             battery_level: batteries,
+            voltage,
+            battery_type: value.status.battery_type.clone(),
+            active_power_source: value.status.active_power_source,
+            power_sources,
+            notifications: value.status.notifications.clone(),
+            cell,
+            capability,
+            install_date: value.status.install_date.clone(),
         };
 
-        let mut lat = 0.00;
-        let mut lng = 0.00;
-
-        if !value.status.coordinates.is_empty()
-            && value.status.coordinates[0].position.coordinates.len() == 2
-        {
-            lat = value.status.coordinates[0].position.coordinates[1];
-            lng = value.status.coordinates[0].position.coordinates[0];
-        }
-
-        let gps = GPS {
-            last_updated_timestamp: last_update,
-            latitude: lat.to_string(),
-            longitude: lng.to_string(),
-        };
+        let gps = value
+            .status
+            .coordinates
+            .first()
+            .and_then(GPS::from_coordinate);
 
+        // Stored photos are counted in the database by the sync.
         let usage = Usage {
-            stored_photos: photo_count,
+            stored_photos: 0,
             photos: photo_count,
         };
 
@@ -130,7 +537,7 @@ impl From<spypoint::Camera> for Camera {
             registration_status: reg_status.clone(),
             created_timestamp: value.activation_date,
             status_file: String::from(""),
-            phone_carrier: String::from(""),
+            phone_carrier,
             account_id: value.user,
             icc_id: value.ucid,
             hardware_version: value.status.version,
@@ -139,14 +546,25 @@ impl From<spypoint::Camera> for Camera {
             status,
             photo_count,
             usage,
-            sd_card: String::from(""),
+            sd_card: sd_card_label(value.status.memory.size),
             gps,
-            zip: String::from(""),
+            zip: String::new(),
+            battery_empty_date: None,
+            subscription,
+            notes: String::new(),
+            location_history: Vec::new(),
+            quota_adjustment: None,
         }
     }
 }
 
 impl Camera {
+    /// Saves the camera. Provider fields are updated, the USER_FIELDS are only set when the
+    /// camera is new so edits made in the web app are kept.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
     pub async fn save(&self, db: &Database) -> crate::Result<()> {
         debug!("cameras::save, camera-> {:?}", self);
         let coll: Collection<Camera> = db.collection(COLLECTION);
@@ -154,18 +572,291 @@ impl Camera {
             "camera_id": &self.camera_id,
         };
 
-        let _ = coll.find_one_and_replace(filter, self).upsert(true).await?;
+        let _ = coll
+            .update_one(filter, self.merge_update()?)
+            .upsert(true)
+            .await?;
+
+        // User fields the sync fills in are set on stored cameras that don't have them yet.
+        for field in USER_FIELDS.iter().filter(|f| f.fill_empty) {
+            let value = (field.get)(self);
+            if value.is_empty() {
+                continue;
+            }
+
+            let filter = doc! {
+                "camera_id": &self.camera_id,
+                field.name: { "$in": ["", null] },
+            };
+            coll.update_one(filter, doc! { "$set": { field.name: value } })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the count of pictures of the camera stored in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// stored_photos: The pictures of the camera in the database.
+    pub async fn save_stored_photos(
+        &mut self,
+        db: &Database,
+        stored_photos: i64,
+    ) -> crate::Result<()> {
+        let coll: Collection<Camera> = db.collection(COLLECTION);
+        let update = doc! {
+            "$set": { "usage.stored_photos": stored_photos },
+        };
+
+        coll.update_one(doc! { "camera_id": &self.camera_id }, update)
+            .await?;
+        self.usage.stored_photos = stored_photos;
+        Ok(())
+    }
+
+    /// Returns the stored camera, None when it isn't stored yet.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// camera_id: The id of the camera.
+    pub async fn find(db: &Database, camera_id: &str) -> crate::Result<Option<Camera>> {
+        let coll: Collection<Camera> = db.collection(COLLECTION);
+        let camera = coll.find_one(doc! { "camera_id": camera_id }).await?;
+
+        Ok(camera)
+    }
+
+    /// Records the config change sent to keep the camera within its quota.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// adjustment: The config change sent.
+    pub async fn save_quota_adjustment(
+        &mut self,
+        db: &Database,
+        adjustment: QuotaAdjustment,
+    ) -> crate::Result<()> {
+        let coll: Collection<Camera> = db.collection(COLLECTION);
+        let update = doc! {
+            "$set": { "quota_adjustment": bson::to_bson(&adjustment)? },
+        };
+
+        coll.update_one(doc! { "camera_id": &self.camera_id }, update)
+            .await?;
+        self.quota_adjustment = Some(adjustment);
+        Ok(())
+    }
+
+    /// Continues the location history of the stored camera. A new period starts when the
+    /// camera was renamed or moved more than RELOCATION_MILES.
+    ///
+    /// Arguments:
+    ///
+    /// previous: The stored camera, None when the camera is new.
+    /// now: The time of the sync.
+    pub fn track_location(&mut self, previous: Option<&Camera>, now: DateTime) {
+        let mut history = previous
+            .map(|p| p.location_history.clone())
+            .unwrap_or_default();
+        let point = self.gps.as_ref().map(|g| g.location.clone());
+
+        match history.last_mut() {
+            Some(current) => {
+                let moved = match (&current.gps, &self.gps) {
+                    (Some(p), Some(gps)) => {
+                        gps.distance_miles(p.coordinates[1], p.coordinates[0]) > RELOCATION_MILES
+                    }
+                    _ => false,
+                };
+
+                if current.name != self.name || moved {
+                    debug!(
+                        "cameras::track_location {} is now {}",
+                        current.name, self.name
+                    );
+                    current.end = Some(now);
+                    history.push(LocationPeriod {
+                        name: self.name.clone(),
+                        gps: point,
+                        start: now,
+                        end: None,
+                    });
+                } else if current.gps.is_none() {
+                    // First fix at the current location.
+                    current.gps = point;
+                }
+            }
+            None => history.push(LocationPeriod {
+                name: self.name.clone(),
+                gps: point,
+                start: DateTime::parse_rfc3339_str(&self.created_timestamp).unwrap_or(now),
+                end: None,
+            }),
+        }
+
+        self.location_history = history;
+    }
+
+    /// Sets the zip code nearest to the camera when it has a gps fix and no zip yet.
+    ///
+    /// Arguments:
+    ///
+    /// zips: The zip code centroids.
+    pub fn apply_zip(&mut self, zips: &ZipCentroids) {
+        if !self.zip.is_empty() {
+            return;
+        }
+
+        if let Some(zip) = self.gps.as_ref().and_then(|gps| zips.nearest(gps)) {
+            self.zip = zip.to_string();
+        }
+    }
+
+    /// Copies the USER_FIELDS of the stored camera, so the camera has the values edited in the
+    /// web app. Fields the sync fills in are only copied when the stored camera has them.
+    ///
+    /// Arguments:
+    ///
+    /// previous: The stored camera.
+    pub fn apply_user_fields(&mut self, previous: &Camera) {
+        for field in &USER_FIELDS {
+            let value = (field.get)(previous);
+            if field.fill_empty && value.is_empty() {
+                continue;
+            }
+            (field.set)(self, value.to_string());
+        }
+    }
+
+    /// Returns the location set in the web app, None when the location is empty or one of the
+    /// names the camera had, which the sync stores when the camera is new.
+    pub fn user_location(&self) -> Option<&str> {
+        let location = self.location.trim();
+        let provider_name =
+            location == self.name || self.location_history.iter().any(|p| p.name == location);
+        if location.is_empty() || provider_name {
+            return None;
+        }
+
+        Some(location)
+    }
+
+    /// Returns the name of the location the camera was at on a date. Dates in the current
+    /// period are at the location set in the web app when there's one. Otherwise the names of
+    /// the history are used, dates before the history are at the first location and the camera
+    /// name is used when there's no history.
+    ///
+    /// Arguments:
+    ///
+    /// date: The date, e.g. when a picture was taken.
+    pub fn location_at(&self, date: DateTime) -> String {
+        let period = self
+            .location_history
+            .iter()
+            .rev()
+            .find(|p| p.start <= date)
+            .or(self.location_history.first());
+
+        let past = matches!(period, Some(p) if p.end.is_some());
+        if let (Some(location), false) = (self.user_location(), past) {
+            return location.to_string();
+        }
+
+        period.map(|p| p.name.clone()).unwrap_or(self.name.clone())
+    }
+
+    /// Returns the update that merges the camera into its stored document, provider fields are
+    /// in $set and the USER_FIELDS in $setOnInsert.
+    pub fn merge_update(&self) -> crate::Result<Document> {
+        let mut set = bson::to_document(self)?;
+        set.remove("_id");
+
+        let mut insert = Document::new();
+        for field in &USER_FIELDS {
+            if let Some(v) = set.remove(field.name) {
+                insert.insert(field.name, v);
+            }
+        }
+
+        Ok(doc! {
+            "$set": set,
+            "$setOnInsert": insert,
+        })
+    }
+
+    /// Creates the indexes of the cameras collection, including the 2dsphere index on the gps
+    /// location used by the geospatial queries.
+    pub async fn create_indexes(db: &Database) -> crate::Result<()> {
+        let coll: Collection<Camera> = db.collection(COLLECTION);
+        let index = IndexModel::builder()
+            .keys(doc! { "gps.location": "2dsphere" })
+            .build();
+
+        coll.create_index(index).await?;
         Ok(())
     }
+
+    /// Returns the cameras within a radius of a point.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// latitude: Latitude of the point in degrees.
+    /// longitude: Longitude of the point in degrees.
+    /// miles: The radius in miles.
+    pub async fn within_miles(
+        db: &Database,
+        latitude: f64,
+        longitude: f64,
+        miles: f64,
+    ) -> crate::Result<Vec<Camera>> {
+        let coll: Collection<Camera> = db.collection(COLLECTION);
+        let filter = doc! {
+            "gps.location": {
+                "$geoWithin": {
+                    "$centerSphere": [[longitude, latitude], miles / EARTH_RADIUS_MILES],
+                },
+            },
+        };
+
+        debug!("cameras::within_miles, filter: {:?}", filter);
+        let mut cursor = coll.find(filter).await?;
+
+        let mut cameras = Vec::new();
+        while cursor.advance().await? {
+            cameras.push(cursor.deserialize_current()?);
+        }
+
+        Ok(cameras)
+    }
+
+    /// Returns all the cameras in the database.
+    pub async fn all(db: &Database) -> crate::Result<Vec<Camera>> {
+        let coll: Collection<Camera> = db.collection(COLLECTION);
+        let mut cursor = coll.find(doc! {}).await?;
+
+        let mut cameras = Vec::new();
+        while cursor.advance().await? {
+            cameras.push(cursor.deserialize_current()?);
+        }
+
+        Ok(cameras)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use chrono::Utc;
     use mongodb::bson;
+    use mongodb::bson::{doc, DateTime};
     use serde_json;
 
-    use crate::cameras::Camera;
+    use crate::cameras::{sd_card_label, Camera, GPS, USER_FIELDS};
     use crate::spypoint;
 
     #[test]
@@ -184,6 +875,12 @@ mod tests {
         assert!(duration.num_days().abs() > 2);
     }
 
+    /// Returns the camera of SPY_CAMERA_JSON.
+    pub(crate) fn camera() -> Camera {
+        let sp_camera: spypoint::Camera = serde_json::from_str(SPY_CAMERA_JSON).unwrap();
+        Camera::from(sp_camera)
+    }
+
     #[test]
     fn from_spypoint_camera() {
         let sp_camera: spypoint::Camera = serde_json::from_str(SPY_CAMERA_JSON).unwrap();
@@ -194,11 +891,183 @@ mod tests {
         assert!(!camera.camera_id.is_empty());
         assert!(!camera.location.is_empty());
 
+        let gps = camera.gps.clone().expect("gps fix");
+        assert_eq!(gps.latitude(), 25.544241);
+        assert_eq!(gps.longitude(), -80.439992);
+        assert_eq!(gps.geo_hash, "dhwc3d1murds");
+        assert_eq!(gps.latitude_dms, "N25 32.654460");
+        assert_eq!(camera.status.voltage, 12.183);
+        assert_eq!(camera.status.last_transmission_timestamp, 1721245941);
+        assert_eq!(camera.phone_carrier, "Verizon");
+        assert_eq!(camera.sd_card, "32 GB");
+        assert!(camera.zip.is_empty());
+        assert_eq!(camera.usage.photos, 4);
+        assert_eq!(camera.status.power_sources[0].location, "TRAY1");
+        assert_eq!(camera.status.cell.mcc, 311);
+        assert_eq!(camera.status.cell.network_type, "LTE");
+        assert!(camera.status.capability.video);
+        assert_eq!(
+            camera.status.notifications,
+            vec![spypoint::Notification::SdCardOnePartition]
+        );
+
+        let sub = camera.subscription.clone().expect("subscription");
+        assert_eq!(sub.plan_name, "Free");
+        assert_eq!(sub.photo_count_per_month, 100);
+        assert_eq!(sub.quota_used(), Some(4.0));
+        assert!(!sub.is_auto_renew);
+        assert_eq!(
+            sub.end_date_billing_cycle
+                .unwrap()
+                .try_to_rfc3339_string()
+                .unwrap(),
+            "2024-08-17T23:43:19.163Z"
+        );
+
         let json = serde_json::to_string(&camera).unwrap();
         println!("{json}");
     }
 
-    const SPY_CAMERA_JSON: &str = r#"{
+    #[test]
+    fn merge_update() {
+        let update = camera().merge_update().unwrap();
+
+        let set = update.get_document("$set").unwrap();
+        assert_eq!(set.get_str("name").unwrap(), "FLEX-3TME");
+        assert!(set.contains_key("status"));
+        assert!(!set.contains_key("_id"));
+
+        let insert = update.get_document("$setOnInsert").unwrap();
+        for field in &USER_FIELDS {
+            assert!(insert.contains_key(field.name), "{} on insert", field.name);
+            assert!(!set.contains_key(field.name), "{} not updated", field.name);
+        }
+        assert_eq!(insert.get_str("location").unwrap(), "FLEX-3TME");
+
+        // The accessors of the user fields match their names in the document.
+        let mut c = camera();
+        for field in &USER_FIELDS {
+            (field.set)(&mut c, format!("user {}", field.name));
+        }
+        let document = bson::to_document(&c).unwrap();
+        for field in &USER_FIELDS {
+            let value = format!("user {}", field.name);
+            assert_eq!((field.get)(&c), value);
+            assert_eq!(document.get_str(field.name).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn location_history() {
+        let mut c = camera();
+        let created = DateTime::parse_rfc3339_str(&c.created_timestamp).unwrap();
+        let day = |d: i64| DateTime::from_millis(created.timestamp_millis() + d * 86_400_000);
+
+        c.track_location(None, day(1));
+        assert_eq!(c.location_history.len(), 1);
+        assert_eq!(c.location_history[0].start, created);
+
+        // Same name and place.
+        let previous = c.clone();
+        c.track_location(Some(&previous), day(2));
+        assert_eq!(c.location_history.len(), 1);
+
+        // Renamed.
+        let previous = c.clone();
+        c.name = String::from("Clover Field");
+        c.track_location(Some(&previous), day(3));
+        assert_eq!(c.location_history.len(), 2);
+        assert_eq!(c.location_history[0].end, Some(day(3)));
+
+        // Moved about a mile.
+        let previous = c.clone();
+        c.gps = Some(GPS::new(25.558, -80.439992));
+        c.track_location(Some(&previous), day(4));
+        assert_eq!(c.location_history.len(), 3);
+        assert_eq!(c.location_history[2].name, "Clover Field");
+
+        assert_eq!(c.location_at(day(-1)), "FLEX-3TME");
+        assert_eq!(c.location_at(day(2)), "FLEX-3TME");
+        assert_eq!(c.location_at(day(3)), "Clover Field");
+        assert_eq!(c.location_at(day(5)), "Clover Field");
+
+        // The location set in the web app is used for the current period.
+        let mut stored = c.clone();
+        stored.location = String::from("North Ridge");
+        c.apply_user_fields(&stored);
+        assert_eq!(c.user_location(), Some("North Ridge"));
+        assert_eq!(c.location_at(day(5)), "North Ridge");
+        assert_eq!(c.location_at(day(3)), "Clover Field");
+    }
+
+    #[test]
+    fn legacy_gps() {
+        let mut document = bson::to_document(&camera()).unwrap();
+        document.insert(
+            "gps",
+            doc! {
+                "last_updated_timestamp": "2024-07-17T19:52:21Z",
+                "longitude": "-80.439992",
+                "latitude": "25.544241",
+            },
+        );
+
+        let c: Camera = bson::from_document(document.clone()).unwrap();
+        let gps = c.gps.expect("gps fix");
+        assert_eq!(gps.latitude(), 25.544241);
+        assert_eq!(gps.longitude(), -80.439992);
+        assert_eq!(gps.last_updated_timestamp.timestamp_millis(), 1721245941000);
+
+        // No fix.
+        document.insert(
+            "gps",
+            doc! {
+                "last_updated_timestamp": "2024-07-17T19:52:21Z",
+                "longitude": "0",
+                "latitude": "0",
+            },
+        );
+        let c: Camera = bson::from_document(document.clone()).unwrap();
+        assert!(c.gps.is_none());
+
+        document.insert("gps", bson::Bson::Null);
+        let c: Camera = bson::from_document(document.clone()).unwrap();
+        assert!(c.gps.is_none());
+
+        // The current shape still reads.
+        let c: Camera = bson::from_document(bson::to_document(&camera()).unwrap()).unwrap();
+        assert_eq!(c.gps.unwrap().geo_hash, "dhwc3d1murds");
+    }
+
+    #[test]
+    fn no_gps_fix() {
+        let mut sp_camera: spypoint::Camera = serde_json::from_str(SPY_CAMERA_JSON).unwrap();
+        sp_camera.status.coordinates[0].position.coordinates = vec![0.0, 0.0];
+        assert!(Camera::from(sp_camera.clone()).gps.is_none());
+
+        sp_camera.status.coordinates.clear();
+        assert!(Camera::from(sp_camera).gps.is_none());
+    }
+
+    #[test]
+    fn sd_card() {
+        assert_eq!(sd_card_label(0), "");
+        assert_eq!(sd_card_label(7580), "8 GB");
+        assert_eq!(sd_card_label(29798), "32 GB");
+        assert_eq!(sd_card_label(1_000_000), "977 GB");
+    }
+
+    #[test]
+    fn distance_miles() {
+        let gps = GPS::new(25.544241, -80.439992);
+
+        assert!(gps.distance_miles(25.544241, -80.439992) < 0.001);
+        // Homestead to Miami International Airport is about 21 miles.
+        let d = gps.distance_miles(25.7959, -80.2870);
+        assert!((d - 20.5).abs() < 1.0, "distance {}", d);
+    }
+
+    pub(crate) const SPY_CAMERA_JSON: &str = r#"{
     "activationDate": "2024-07-17T23:43:19.162Z",
     "config": {
       "batteryType": "AUTO",
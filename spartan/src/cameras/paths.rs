@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use chrono::Datelike;
+
+use crate::cameras::pictures::Picture;
+
+/// Default layout of the objects of a picture in the bucket.
+pub const DEFAULT_TEMPLATE: &str = "locations/{camera_id}/{yyyy}/{mm}/{id}-{rendition}.jpg";
+
+/// Placeholders a path template can use.
+pub const PLACEHOLDERS: [&str; 9] = [
+    "camera_id",
+    "camera_name",
+    "yyyy",
+    "mm",
+    "dd",
+    "id",
+    "photo_id",
+    "tag",
+    "rendition",
+];
+
+/// The stored versions of a picture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rendition {
+    Full,
+    Thumb,
+}
+
+impl Rendition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rendition::Full => "full",
+            Rendition::Thumb => "thumb",
+        }
+    }
+}
+
+/// Template of the object path of a picture, e.g. "{camera_id}/{yyyy}/{id}-{rendition}.jpg".
+///
+/// ## Placeholders
+/// camera_id: The id of the camera.
+/// camera_name: The name of the camera when the picture was taken, sanitized for object keys.
+/// yyyy, mm, dd: The date the picture was taken, zero padded.
+/// id: The id of the picture in the database.
+/// photo_id: The id of the photo at the provider.
+/// tag: The first tag of the picture, "untagged" when it has none.
+/// rendition: "full" or "thumb".
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    template: String,
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        PathTemplate {
+            template: String::from(DEFAULT_TEMPLATE),
+        }
+    }
+}
+
+impl PathTemplate {
+    /// Parses a template. The template must only use known placeholders, and include the
+    /// rendition and an id so every object gets its own path.
+    ///
+    /// Arguments:
+    ///
+    /// template: The path template.
+    pub fn new(template: &str) -> crate::Result<Self> {
+        let mut used = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                return Err(Box::from(format!(
+                    "paths::new unclosed placeholder in {}",
+                    template
+                )));
+            };
+
+            let name = &rest[start + 1..start + len];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(Box::from(format!(
+                    "paths::new unknown placeholder {{{}}}",
+                    name
+                )));
+            }
+
+            used.push(name);
+            rest = &rest[start + len + 1..];
+        }
+
+        if !used.contains(&"rendition") || !(used.contains(&"id") || used.contains(&"photo_id")) {
+            return Err(Box::from(format!(
+                "paths::new template {} needs {{rendition}} and {{id}} or {{photo_id}}",
+                template
+            )));
+        }
+
+        Ok(PathTemplate {
+            template: template.to_string(),
+        })
+    }
+
+    /// Returns the start of the template before the first placeholder, e.g. "locations/".
+    pub fn prefix(&self) -> &str {
+        let end = self.template.find('{').unwrap_or(self.template.len());
+        &self.template[..end]
+    }
+
+    /// Returns the placeholder values of a path made by the template, None when the path
+    /// doesn't match the template. A value runs up to the text that follows its placeholder.
+    ///
+    /// Arguments:
+    ///
+    /// path: The object path.
+    pub fn parse(&self, path: &str) -> Option<HashMap<String, String>> {
+        // Splits the template into text and placeholders.
+        let mut parts = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..].find('}')?;
+            parts.push((false, &rest[..start]));
+            parts.push((true, &rest[start + 1..start + len]));
+            rest = &rest[start + len + 1..];
+        }
+        parts.push((false, rest));
+
+        let mut values = HashMap::new();
+        let mut rest = path;
+        for (i, (placeholder, part)) in parts.iter().enumerate() {
+            if !placeholder {
+                rest = rest.strip_prefix(part)?;
+                continue;
+            }
+
+            let end = match parts.get(i + 1) {
+                Some((false, text)) if !text.is_empty() => rest.find(text)?,
+                _ => rest.len(),
+            };
+
+            values.insert(part.to_string(), rest[..end].to_string());
+            rest = &rest[end..];
+        }
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        Some(values)
+    }
+
+    /// Returns the object path of a rendition of the picture.
+    ///
+    /// Arguments:
+    ///
+    /// picture: The picture, its id and location need to be set.
+    /// rendition: The stored version of the picture.
+    pub fn render(&self, picture: &Picture, rendition: Rendition) -> String {
+        let date = picture.created.to_chrono();
+        let tag = picture
+            .tags
+            .first()
+            .map(|t| sanitize(t))
+            .unwrap_or(String::from("untagged"));
+
+        let values = [
+            ("camera_id", picture.camera_id.clone()),
+            ("camera_name", sanitize(&picture.location)),
+            ("yyyy", format!("{:04}", date.year())),
+            ("mm", format!("{:02}", date.month())),
+            ("dd", format!("{:02}", date.day())),
+            ("id", picture.id.map(|i| i.to_hex()).unwrap_or_default()),
+            ("photo_id", picture.photo_id.clone()),
+            ("tag", tag),
+            ("rendition", rendition.as_str().to_string()),
+        ];
+
+        let mut path = self.template.clone();
+        for (name, value) in values {
+            path = path.replace(&format!("{{{}}}", name), &value);
+        }
+
+        path
+    }
+}
+
+/// Returns a name that is safe in an object key. Characters other than letters, digits, '.',
+/// '_' and '-' become '-', and repeated '-' are collapsed.
+pub fn sanitize(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        let c = if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
+            c
+        } else {
+            '-'
+        };
+
+        if c == '-' && out.ends_with('-') {
+            continue;
+        }
+        out.push(c);
+    }
+
+    let out = out.trim_matches(|c| c == '-' || c == '.').to_string();
+    if out.is_empty() {
+        return String::from("unnamed");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::DateTime;
+
+    use crate::cameras::paths::{sanitize, PathTemplate, Rendition};
+    use crate::cameras::pictures::Picture;
+    use crate::spypoint::Photo;
+
+    #[test]
+    fn render() {
+        let mut picture = Picture::from(Photo::default());
+        picture.id = Some(ObjectId::parse_str("66985496c6eb10dbad5c51f6").unwrap());
+        picture.camera_id = String::from("5f145aaf173ca3001571df15");
+        picture.location = String::from("Clover Field / North");
+        picture.created = DateTime::parse_rfc3339_str("2024-07-05T19:51:41.000Z").unwrap();
+
+        let t = PathTemplate::default();
+        assert_eq!(
+            t.render(&picture, Rendition::Thumb),
+            "locations/5f145aaf173ca3001571df15/2024/07/66985496c6eb10dbad5c51f6-thumb.jpg"
+        );
+
+        let t =
+            PathTemplate::new("{camera_name}/{yyyy}-{mm}-{dd}/{tag}/{id}-{rendition}.jpg").unwrap();
+        assert_eq!(
+            t.render(&picture, Rendition::Full),
+            "Clover-Field-North/2024-07-05/untagged/66985496c6eb10dbad5c51f6-full.jpg"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let t = PathTemplate::default();
+        assert_eq!(t.prefix(), "locations/");
+
+        let values = t
+            .parse("locations/5f145aaf173ca3001571df15/2024/07/66985496c6eb10dbad5c51f6-thumb.jpg")
+            .expect("path matches");
+        assert_eq!(values["camera_id"], "5f145aaf173ca3001571df15");
+        assert_eq!(values["mm"], "07");
+        assert_eq!(values["id"], "66985496c6eb10dbad5c51f6");
+        assert_eq!(values["rendition"], "thumb");
+
+        assert!(t
+            .parse("locations/5f145aaf173ca3001571df15/7-2024/66985496.jpg")
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_template() {
+        assert!(PathTemplate::new("{camera_id}/{id}.jpg").is_err());
+        assert!(PathTemplate::new("{camera}/{id}-{rendition}.jpg").is_err());
+        assert!(PathTemplate::new("{camera_id/{id}-{rendition}.jpg").is_err());
+        assert!(PathTemplate::new("{photo_id}-{rendition}.jpg").is_ok());
+    }
+
+    #[test]
+    fn sanitize_name() {
+        assert_eq!(sanitize("FLEX-3TME"), "FLEX-3TME");
+        assert_eq!(sanitize(" Deer  Stand #2 "), "Deer-Stand-2");
+        assert_eq!(sanitize("../"), "unnamed");
+    }
+}
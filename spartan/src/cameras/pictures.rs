@@ -1,23 +1,97 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use bytes::Bytes;
-use chrono::{Datelike, Utc};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use cloud_storage::Object;
+use futures_util::StreamExt;
 use image;
-use image::{ImageFormat, RgbImage};
+use image::{DynamicImage, ImageFormat, RgbImage};
 use image::codecs::jpeg::JpegEncoder;
 use image::imageops::FilterType;
-use log::{debug, error};
-use mongodb::{bson, Collection, Database};
-use mongodb::bson::{DateTime, doc};
-use reqwest::Client;
+use log::{debug, error, info, warn};
+use mongodb::{bson, Collection, Database, IndexModel};
+use mongodb::bson::{Binary, DateTime, doc, Document};
+use mongodb::bson::spec::BinarySubtype;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Method, Response};
 use serde::{Deserialize, Serialize};
 
-use crate::spypoint::Photo;
+use crate::cameras::{astro, banner, Camera, GPS};
+use crate::cameras::detect;
+use crate::cameras::detect::{Detections, Detector};
+use crate::cameras::enhance;
+use crate::cameras::enhance::{EnhanceOptions, Light};
+use crate::cameras::metadata;
+use crate::cameras::metadata::Exif;
+use crate::cameras::paths::{PathTemplate, Rendition};
+use crate::spypoint::{Hd, Header, Photo};
 use crate::sys::gdrive;
 use crate::sys::gdrive::GCPClient;
+use crate::sys::weather;
+use crate::sys::weather::{Observation, WeatherProvider};
 
 const COLLECTION: &str = "pictures";
 
+/// A rendition that doesn't download within this time is given up on.
+const DOWNLOAD_TIMEOUT_SECS: u64 = 120;
+
+/// Downloads of a picture that are tried when the bytes aren't a whole JPEG.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Hours an upgrade waits after a failed attempt, doubled after each failure up to
+/// UPGRADE_MAX_BACKOFF_HOURS.
+const UPGRADE_BACKOFF_HOURS: i64 = 6;
+const UPGRADE_MAX_BACKOFF_HOURS: i64 = 7 * 24;
+
+/// Width and height of the signature frames are compared by to find empty frames.
+const SIGNATURE_WIDTH: u32 = 32;
+const SIGNATURE_HEIGHT: u32 = 24;
+/// Signature cells that differ by more than this from the reference, after evening out the
+/// brightness of the frames, are changed.
+const CELL_CHANGE: i32 = 24;
+/// Frames with less than this share of changed cells compared with a reference are likely
+/// empty, by default. Moving branches change a few cells a little, an animal changes a block of
+/// cells, but a small or distant animal may change fewer.
+pub const EMPTY_MAX_CHANGED: f32 = 0.015;
+/// Recent frames of the camera a picture is compared with.
+const EMPTY_REFERENCE_FRAMES: i64 = 5;
+/// Frames of the same trigger are left out of the reference, an animal standing still would
+/// look empty.
+const EMPTY_REFERENCE_GAP_MINUTES: i64 = 10;
+
+/// Start of a JPEG file, the start of image marker and the first byte of the next marker.
+const JPEG_MAGIC: [u8; 3] = [0xFF, 0xD8, 0xFF];
+
+/// End of image marker of a JPEG file.
+const JPEG_EOI: [u8; 2] = [0xFF, 0xD9];
+
+/// The downloaded bytes aren't a whole JPEG image.
+#[derive(Debug, Clone)]
+pub struct InvalidImage {
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("invalid image, {}", self.reason))
+    }
+}
+
+impl std::error::Error for InvalidImage {}
+
+/// Collects what passes through a download stream.
+struct DownloadSink {
+    md5: md5::Context,
+    bytes: Vec<u8>,
+    /// Set when the first bytes aren't a JPEG, the upload is stopped before they are stored.
+    invalid: Option<InvalidImage>,
+}
+
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 #[serde(default)]
 pub struct WindDirection {
@@ -39,6 +113,153 @@ pub struct WeatherData {
     pub wind_direction: WindDirection,
 }
 
+/// Options for the processing done on a picture while it is uploaded.
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    /// Reads the temperature, moon phase and timestamp from the info strip.
+    pub read_banner: bool,
+    /// Removes the info strip from the thumbnail.
+    pub strip_banner: bool,
+    /// Height of the info strip as a ratio of the picture height.
+    pub banner_ratio: f32,
+    /// Layout of the picture objects in the bucket.
+    pub path_template: PathTemplate,
+    /// Processing of the thumbnail.
+    pub enhance: EnhanceOptions,
+    /// Finds animals, people and vehicles in the picture, detection is off when None.
+    pub detector: Option<Arc<dyn Detector>>,
+    /// What is stored of frames that are likely empty.
+    pub empty_frames: EmptyFrames,
+    /// Frames with less than this share of changed signature cells compared with a recent
+    /// frame are likely empty.
+    pub empty_max_changed: f32,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            read_banner: false,
+            strip_banner: false,
+            banner_ratio: banner::BANNER_HEIGHT_RATIO,
+            path_template: PathTemplate::default(),
+            enhance: EnhanceOptions::default(),
+            detector: None,
+            empty_frames: EmptyFrames::Keep,
+            empty_max_changed: EMPTY_MAX_CHANGED,
+        }
+    }
+}
+
+/// What is stored of a frame that is likely empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyFrames {
+    /// The picture and its thumbnail.
+    #[default]
+    Keep,
+    /// Only the thumbnail.
+    Thumbnail,
+    /// Nothing, the picture is only recorded in the database so it isn't downloaded again. Only
+    /// frames the detection model found empty are skipped, the others get a thumbnail.
+    Skip,
+}
+
+impl EmptyFrames {
+    /// Returns what is stored of a likely empty frame with the detections. A skipped frame is
+    /// gone for good, so Skip needs the model to confirm the frame is empty.
+    ///
+    /// Arguments:
+    ///
+    /// detections: What the detection model found in the picture, None when it didn't run.
+    pub fn for_detections(self, detections: Option<&Detections>) -> Self {
+        let confirmed = detections.is_some_and(|d| d.labels.iter().all(|l| l == detect::EMPTY));
+        match self {
+            EmptyFrames::Skip if !confirmed => EmptyFrames::Thumbnail,
+            mode => mode,
+        }
+    }
+}
+
+impl From<&str> for EmptyFrames {
+    /// Unknown values are Keep.
+    fn from(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "thumbnail" => EmptyFrames::Thumbnail,
+            "skip" => EmptyFrames::Skip,
+            _ => EmptyFrames::Keep,
+        }
+    }
+}
+
+/// Size of a photo at the provider, ordered from small to large.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    Small,
+    Medium,
+    #[default]
+    Large,
+}
+
+impl Quality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Small => "small",
+            Quality::Medium => "medium",
+            Quality::Large => "large",
+        }
+    }
+
+    /// Returns the rendition of the photo in this quality.
+    pub fn of(self, photo: &Photo) -> &Hd {
+        match self {
+            Quality::Small => &photo.small,
+            Quality::Medium => &photo.medium,
+            Quality::Large => &photo.large,
+        }
+    }
+}
+
+impl From<&str> for Quality {
+    /// Unknown values are Large.
+    fn from(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "small" => Quality::Small,
+            "medium" => Quality::Medium,
+            _ => Quality::Large,
+        }
+    }
+}
+
+/// A rendition of a photo at the provider.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhotoSource {
+    pub quality: Quality,
+    pub url: String,
+    /// HTTP method of the url, GET when empty.
+    pub verb: String,
+    /// Headers the provider requires when downloading the url.
+    pub headers: Vec<Header>,
+}
+
+impl PhotoSource {
+    /// Returns the rendition of the photo in the quality.
+    ///
+    /// Arguments:
+    ///
+    /// photo: The photo.
+    /// quality: The quality of the rendition.
+    pub fn from_photo(photo: &Photo, quality: Quality) -> Self {
+        let hd = quality.of(photo);
+        PhotoSource {
+            quality,
+            url: format!("https://{}/{}", hd.host, hd.path),
+            verb: hd.verb.clone(),
+            headers: hd.headers.clone(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Picture {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
@@ -59,16 +280,82 @@ pub struct Picture {
     #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
     pub created: DateTime,
     pub photo_time_stamp: String,
+    /// Url of the rendition that was downloaded.
     pub photo_url: String,
+    /// HTTP method of the photo url, GET when empty.
+    #[serde(skip)]
+    pub photo_verb: String,
+    /// Headers the provider requires when downloading the photo url.
+    #[serde(skip)]
+    pub photo_headers: Vec<Header>,
+    /// Quality of the stored photo. Before the upload it's the quality wanted, a smaller one is
+    /// stored when it isn't available.
+    #[serde(default)]
+    pub quality: Quality,
+    /// The renditions of the photo at the provider, largest first. Their urls expire so they
+    /// aren't stored.
+    #[serde(skip)]
+    pub sources: Vec<PhotoSource>,
+    /// Size in bytes of the stored picture, 0 when not recorded.
+    #[serde(default)]
+    pub size: i64,
+    /// Base64 MD5 hash of the stored picture as reported by cloud storage, empty when not
+    /// recorded.
+    #[serde(default)]
+    pub md5_hash: String,
+    /// Tags of the photo at the provider, e.g. "day".
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub weather_data: Option<WeatherData>,
+    pub exif: Option<Exif>,
+    /// Light the picture was taken in, read from its colors on upload.
+    #[serde(default)]
+    pub light: Light,
+    /// Whether the light disagrees with the sun phase at the camera, e.g. a color frame at
+    /// night.
+    #[serde(default)]
+    pub light_mismatch: bool,
+    /// What the detection model found in the picture, None when it didn't run.
+    #[serde(default)]
+    pub detections: Option<Detections>,
+    /// Small grayscale version of the frame, compared with later frames of the camera to find
+    /// empty frames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Binary>,
+    /// Whether the frame barely differs from recent frames of the camera, e.g. a trigger by
+    /// moving branches. The path is empty when the picture wasn't kept.
+    #[serde(default)]
+    pub likely_empty: bool,
+    /// Failed attempts to upgrade the picture to a better rendition since the last upgrade.
+    #[serde(default)]
+    pub upgrade_attempts: i64,
+    /// When the last upgrade attempt failed, the next one waits for the backoff.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade_failed_at: Option<DateTime>,
 }
 
 impl From<Photo> for Picture {
     fn from(value: Photo) -> Self {
+        Picture::from_photo(value, Quality::Large)
+    }
+}
+
+impl Picture {
+    /// Converts a photo of the provider, the picture is downloaded in the quality.
+    ///
+    /// Arguments:
+    ///
+    /// value: The photo.
+    /// quality: The rendition of the photo to download.
+    pub fn from_photo(value: Photo, quality: Quality) -> Self {
         let pic_date =
             DateTime::parse_rfc3339_str(value.origin_date.clone()).unwrap_or(DateTime::now());
 
-        let url = format!("https://{}/{}", value.large.host, value.large.path);
+        let sources: Vec<PhotoSource> = [Quality::Large, Quality::Medium, Quality::Small]
+            .into_iter()
+            .map(|q| PhotoSource::from_photo(&value, q))
+            .collect();
+        let source = PhotoSource::from_photo(&value, quality);
 
         Picture {
             id: None,
@@ -85,13 +372,26 @@ impl From<Photo> for Picture {
             last_updated: pic_date,
             created: pic_date,
             photo_time_stamp: value.origin_date.clone(),
-            photo_url: url,
+            photo_url: source.url,
+            photo_verb: source.verb,
+            photo_headers: source.headers,
+            quality,
+            sources,
+            size: 0,
+            md5_hash: String::new(),
+            tags: value.tag,
             weather_data: None,
+            exif: None,
+            light: Light::Unknown,
+            light_mismatch: false,
+            detections: None,
+            signature: None,
+            likely_empty: false,
+            upgrade_attempts: 0,
+            upgrade_failed_at: None,
         }
     }
-}
 
-impl Picture {
     /// Saves a picture to the database
     ///
     /// Arguments:
@@ -104,6 +404,141 @@ impl Picture {
         Ok(())
     }
 
+    /// Creates the indexes of the pictures collection, on the light used to filter day and night
+    /// activity and on the detected labels.
+    pub async fn create_indexes(db: &Database) -> crate::Result<()> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let index = IndexModel::builder()
+            .keys(doc! { "camera_id": 1, "light": 1, "date": -1 })
+            .build();
+        coll.create_index(index).await?;
+
+        let index = IndexModel::builder()
+            .keys(doc! { "detections.labels": 1, "date": -1 })
+            .build();
+        coll.create_index(index).await?;
+
+        Ok(())
+    }
+
+    /// Returns the picture of a provider photo, None when it isn't in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    /// photo_id: The id of the photo at the provider.
+    pub async fn find_by_photo_id(db: &Database, photo_id: &str) -> crate::Result<Option<Picture>> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let picture = coll.find_one(doc! { "photo_id": photo_id }).await?;
+
+        Ok(picture)
+    }
+
+    /// Returns the picture with the id, None when it isn't in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    /// id: The id of the picture.
+    pub async fn find_by_id(
+        db: &Database,
+        id: bson::oid::ObjectId,
+    ) -> crate::Result<Option<Picture>> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let picture = coll.find_one(doc! { "_id": id }).await?;
+
+        Ok(picture)
+    }
+
+    /// Returns the object paths, full pictures and thumbnails, of the pictures in a bucket.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    /// bucket: The name of the bucket.
+    pub async fn stored_paths(db: &Database, bucket: &str) -> crate::Result<HashSet<String>> {
+        let coll: Collection<Document> = db.collection(COLLECTION);
+        let mut cursor = coll
+            .find(doc! { "bucket": bucket })
+            .projection(doc! { "path": 1, "thumb_path": 1 })
+            .await?;
+
+        let mut paths = HashSet::new();
+        while cursor.advance().await? {
+            let d = cursor.current();
+            for field in ["path", "thumb_path"] {
+                if let Ok(p) = d.get_str(field) {
+                    paths.insert(p.to_string());
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns the number of pictures of a camera in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    /// camera_id: The id of the camera.
+    pub async fn count(db: &Database, camera_id: &str) -> crate::Result<i64> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let count = coll
+            .count_documents(doc! { "camera_id": camera_id })
+            .await?;
+
+        Ok(count as i64)
+    }
+
+    /// Updates the object paths of a picture in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    pub async fn update_paths(&self, db: &Database) -> crate::Result<()> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let filter = doc! {
+            "photo_id": &self.photo_id,
+        };
+        let update = doc! {
+            "$set": { "path": &self.path, "thumb_path": &self.thumb_path },
+        };
+
+        coll.update_one(filter, update).await?;
+        Ok(())
+    }
+
+    /// Returns a cursor over all the pictures in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    pub async fn cursor(db: &Database) -> crate::Result<mongodb::Cursor<Picture>> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let cursor = coll.find(doc! {}).await?;
+
+        Ok(cursor)
+    }
+
+    /// Updates the weather data of a picture in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    pub async fn update_weather(&self, db: &Database) -> crate::Result<()> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let filter = doc! {
+            "photo_id": &self.photo_id,
+        };
+        let update = doc! {
+            "$set": { "weather_data": bson::to_bson(&self.weather_data)? },
+        };
+
+        coll.update_one(filter, update).await?;
+        Ok(())
+    }
+
     pub fn within_days(&self, days: i64) -> bool {
         let pic_date = self.date.to_chrono();
         let now = Utc::now();
@@ -144,99 +579,463 @@ impl Picture {
         Ok(true)
     }
 
-    /// Downloads an image from the client provider.
+    /// Stores the EXIF metadata on the picture. When the provider's origin date could not be
+    /// parsed, the EXIF capture time is used as the picture date.
+    ///
+    /// Arguments:
+    ///
+    /// exif: The EXIF metadata read from the downloaded image.
+    pub fn apply_exif(&mut self, exif: Option<Exif>) {
+        let Some(exif) = exif else {
+            return;
+        };
+
+        if DateTime::parse_rfc3339_str(&self.photo_time_stamp).is_err() {
+            if let Some(d) = exif.capture_date() {
+                debug!(
+                    "pictures::apply_exif using exif capture time for photo_id: {}",
+                    self.photo_id
+                );
+                self.date = d;
+                self.created = d;
+                self.last_updated = d;
+            }
+        }
+
+        self.exif = Some(exif);
+    }
+
+    /// Reads the info strip of the picture into the weather data, filling what the weather
+    /// provider didn't set. Returns the picture without the strip when `strip_banner` is set,
+    /// otherwise the picture is returned unchanged.
+    ///
+    /// Arguments:
+    ///
+    /// img: The decoded picture.
+    /// options: Upload options with the banner settings.
+    pub fn process_banner(&mut self, img: DynamicImage, options: &UploadOptions) -> DynamicImage {
+        let Some((body, strip)) = banner::split_banner(&img, options.banner_ratio) else {
+            debug!(
+                "pictures::process_banner no info strip found, photo_id: {}",
+                self.photo_id
+            );
+            return img;
+        };
+
+        if options.read_banner {
+            let b = banner::read_banner(&strip);
+            let weather = self.weather_data.get_or_insert_with(WeatherData::default);
+            // The weather provider and the computed moon phase are more reliable than the
+            // strip, only what they didn't set is filled.
+            if weather.observation_time.is_empty() {
+                if let Some(t) = b.temperature {
+                    weather.temperature = t;
+                }
+                if let Some(time) = b.timestamp_rfc3339() {
+                    weather.observation_time = time;
+                }
+            }
+            if !b.moon_phase.is_empty() && weather.moon_phase.is_empty() {
+                weather.moon_phase = b.moon_phase;
+            }
+        }
+
+        if options.strip_banner {
+            return body;
+        }
+
+        img
+    }
+
+    /// Stores a weather observation in the weather data of the picture. Fields that don't come
+    /// from the weather provider, like the sun and moon phase, are kept.
+    ///
+    /// Arguments:
+    ///
+    /// observation: The weather at the camera when the picture was taken.
+    pub fn apply_weather(&mut self, observation: &Observation) {
+        let (short, long) = weather::cardinal_labels(observation.wind_degrees);
+        let w = self.weather_data.get_or_insert_with(WeatherData::default);
+
+        w.barometric_pressure = observation.barometric_pressure;
+        w.temperature = observation.temperature.round() as i64;
+        w.weather_label.clone_from(&observation.conditions);
+        w.observation_time.clone_from(&observation.observation_time);
+        w.wind_direction = WindDirection {
+            cardinal_label_short: short.to_string(),
+            speed: observation.wind_speed,
+            degrees: observation.wind_degrees,
+            cardinal_label: long.to_string(),
+        };
+    }
+
+    /// Computes the sun phase (when the camera has a gps fix) and the moon phase for the date
+    /// the picture was taken and stores them in the weather data.
+    ///
+    /// Arguments:
+    ///
+    /// gps: The location of the camera that took the picture.
+    pub fn apply_astronomy(&mut self, gps: Option<&GPS>) {
+        let date = self.date.to_chrono();
+        let w = self.weather_data.get_or_insert_with(WeatherData::default);
+
+        if let Some(gps) = gps {
+            w.sun_phase = astro::sun_phase(date, gps.latitude(), gps.longitude()).to_string();
+        }
+        w.moon_phase = astro::moon_phase(date).name().to_string();
+    }
+
+    /// Returns the renditions to try in order, the quality wanted first and then the smaller
+    /// ones. Pictures without renditions use the photo url.
+    pub fn fallback_sources(&self) -> Vec<PhotoSource> {
+        if self.sources.is_empty() {
+            return vec![PhotoSource {
+                quality: self.quality,
+                url: self.photo_url.clone(),
+                verb: self.photo_verb.clone(),
+                headers: self.photo_headers.clone(),
+            }];
+        }
+
+        let mut sources: Vec<PhotoSource> = self
+            .sources
+            .iter()
+            .filter(|s| s.quality <= self.quality)
+            .cloned()
+            .collect();
+        sources.sort_by_key(|s| std::cmp::Reverse(s.quality));
+        sources
+    }
+
+    /// Starts the download of an image from the client provider, the body is read by the
+    /// caller. When a rendition fails, the next smaller one is tried, the photo url and
+    /// quality are set to the rendition that was downloaded.
+    ///
+    /// Arguments:
+    ///
+    /// client: The client provider from where to get the picture from.
+    pub async fn download(&mut self, client: &Client) -> crate::Result<Response> {
+        let mut last_err = None;
+
+        for source in self.fallback_sources() {
+            match fetch(client, &source).await {
+                Ok(resp) => {
+                    if source.quality != self.quality {
+                        warn!(
+                            "pictures::download photo_id: {}, {} unavailable, storing {}",
+                            self.photo_id,
+                            self.quality.as_str(),
+                            source.quality.as_str()
+                        );
+                    }
+
+                    self.photo_url = source.url;
+                    self.photo_verb = source.verb;
+                    self.photo_headers = source.headers;
+                    self.quality = source.quality;
+                    return Ok(resp);
+                }
+                Err(e) => {
+                    warn!(
+                        "pictures::download photo_id: {}, {} failed, {:?}",
+                        self.photo_id,
+                        source.quality.as_str(),
+                        e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            Box::from(format!(
+                "pictures::download no rendition to download, photo_id: {}",
+                self.photo_id
+            ))
+        }))
+    }
+
+    /// Downloads the image and streams it to cloud storage. The download is teed into a md5
+    /// hash and into the buffer the thumbnail is decoded from, so the image is held in memory
+    /// once. The content type and the start of the JPEG are checked before the first bytes are
+    /// stored, the end of image and the full decode before the object is kept, an object that
+    /// fails is deleted. Returns the stored object, the downloaded bytes and the decoded image.
     ///
     /// Arguments:
     ///
     /// client: The client provider from where to get the picture from.
-    pub async fn download_image(&self, client: &Client) -> reqwest::Result<Bytes> {
-        client.get(&self.photo_url).send().await?.bytes().await
+    /// gcp_client: Google cloud storage client.
+    /// path: The path of the object.
+    pub async fn stream_to_bucket(
+        &mut self,
+        client: &Client,
+        gcp_client: &GCPClient,
+        path: &str,
+    ) -> crate::Result<(Object, Vec<u8>, DynamicImage)> {
+        let resp = self.download(client).await?;
+        check_content_type(content_type(&resp))?;
+        let length = resp.content_length();
+
+        let sink = Arc::new(Mutex::new(DownloadSink {
+            md5: md5::Context::new(),
+            bytes: Vec::with_capacity(length.unwrap_or_default() as usize),
+            invalid: None,
+        }));
+
+        let tee = sink.clone();
+        let stream = resp.bytes_stream().map(move |chunk| {
+            let chunk = chunk?;
+            let mut s = tee.lock().unwrap();
+            s.md5.consume(&chunk);
+            s.bytes.extend_from_slice(&chunk);
+
+            // Every chunk passed on agrees with the start of a JPEG.
+            let n = s.bytes.len().min(JPEG_MAGIC.len());
+            if s.bytes[..n] != JPEG_MAGIC[..n] {
+                let invalid = InvalidImage {
+                    reason: String::from("no jpeg magic bytes"),
+                };
+                s.invalid = Some(invalid.clone());
+                return Err(Box::new(invalid) as Box<dyn std::error::Error + Send + Sync>);
+            }
+
+            Ok(chunk)
+        });
+
+        let saved = gcp_client
+            .save_stream(&self.bucket, stream, length, path, gdrive::MIME_JPEG)
+            .await;
+
+        let (invalid, img_bytes, digest) = {
+            let mut s = sink.lock().unwrap();
+            let digest = std::mem::replace(&mut s.md5, md5::Context::new()).compute();
+            (
+                s.invalid.take(),
+                std::mem::take(&mut s.bytes),
+                STANDARD.encode(digest.0),
+            )
+        };
+        if let Some(invalid) = invalid {
+            return Err(Box::new(invalid));
+        }
+        let object = saved?;
+
+        let checked = match validate_jpeg(&img_bytes) {
+            Ok(img) => match object.md5_hash.as_deref() {
+                Some(stored) if !stored.is_empty() && stored != digest => Err(Box::from(format!(
+                    "pictures::stream_to_bucket md5 mismatch, downloaded {}, stored {}",
+                    digest, stored
+                ))),
+                _ => Ok(img),
+            },
+            Err(e) => Err(Box::new(e) as Box<dyn std::error::Error>),
+        };
+
+        match checked {
+            Ok(img) => Ok((object, img_bytes, img)),
+            Err(e) => {
+                if let Err(e) = gcp_client.delete(&self.bucket, path).await {
+                    error!(
+                        "pictures::stream_to_bucket, unable to delete invalid {}, {:?}",
+                        path, e
+                    );
+                }
+                Err(e)
+            }
+        }
     }
 
     /// Uploads pictures to cloud storage. Generates a thumbnail of the picture and uploads that to
     /// cloud storage as well. A new picture record is created in the database for the new picture.
+    /// The upload is all or nothing, objects already written are deleted when a later step fails.
     ///
     /// Arguments:
     ///
     /// db: MongoDB Database
     /// client: Spypoint client used to download picture.
-    /// camera_name: The name of the camera that the picture belongs to.
+    /// camera: The camera that took the picture.
     /// gcp_client: Google cloud storage client.
     /// gcp_bucket: The name of the bucket in cloud storage where the picture will be saved.
-    pub async fn upload(
+    /// weather: Weather provider queried once the date of the picture is final, None when
+    /// weather enrichment is off.
+    /// options: Processing done on the picture before it is saved.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload<W: WeatherProvider>(
         &mut self,
         db: &Database,
         client: &Client,
-        camera_name: String,
+        camera: &Camera,
         gcp_client: &GCPClient,
         gcp_bucket: String,
+        weather: Option<&W>,
+        options: &UploadOptions,
     ) -> crate::Result<()> {
-        // Download Pic
-        let img_bytes = self.download_image(client).await?;
+        self.bucket.clone_from(&gcp_bucket);
 
-        debug!(
-            "pictures::upload Picture Downloaded - {}",
-            self.picture_date
-        );
+        let mut written = Vec::new();
+        if let Err(e) = self
+            .store(
+                db,
+                client,
+                camera,
+                gcp_client,
+                weather,
+                options,
+                &mut written,
+            )
+            .await
+        {
+            for path in &written {
+                if let Err(e) = gcp_client.delete(&gcp_bucket, path).await {
+                    error!(
+                        "pictures::upload, unable to delete {} after failed upload, {:?}",
+                        path, e
+                    );
+                }
+            }
+
+            self.id = None;
+            return Err(e);
+        }
 
+        Ok(())
+    }
+
+    /// Saves the picture and its thumbnail to cloud storage and the picture to the database.
+    /// The paths of the objects saved are added to written.
+    #[allow(clippy::too_many_arguments)]
+    async fn store<W: WeatherProvider>(
+        &mut self,
+        db: &Database,
+        client: &Client,
+        camera: &Camera,
+        gcp_client: &GCPClient,
+        weather: Option<&W>,
+        options: &UploadOptions,
+        written: &mut Vec<String>,
+    ) -> crate::Result<()> {
         // set id on Photo
         let id = bson::oid::ObjectId::new();
         self.id = Some(id);
 
-        // create base path and image path
-        let created = self.created.to_chrono();
+        // The location is part of the path when the template uses the camera name. The path
+        // is set before the download with the provider's date, and corrected once the EXIF
+        // date is known.
+        self.location = camera.location_at(self.date);
+        let img_path = options.path_template.render(self, Rendition::Full);
 
-        let base_path = format!(
-            "locations/{}/{}-{}",
-            camera_name,
-            created.month(),
-            created.year()
-        );
-
-        let img_path = format!("{}/{}.jpg", base_path, id.to_hex());
-
-        self.bucket.clone_from(&gcp_bucket);
-        self.path.clone_from(&img_path);
-
-        // Save Image to cloud storage
-        if let Err(e) = gcp_client
-            .save_to_bucket(
-                gcp_bucket.as_str(),
-                img_bytes.clone().to_vec(),
-                img_path.as_str(),
-                gdrive::MIME_JPEG,
-            )
-            .await
-        {
-            error!(
-                "pictures::upload, error uploading to cloud storage, {:?}",
-                e
-            );
-            return Err(Box::from(e));
+        // Download Pic and stream it to cloud storage. A download that isn't a whole JPEG is
+        // deleted and retried.
+        let mut attempt = 1;
+        let (object, img_bytes, img) = loop {
+            match self.stream_to_bucket(client, gcp_client, &img_path).await {
+                Ok(stored) => break stored,
+                Err(e) if e.is::<InvalidImage>() && attempt < DOWNLOAD_ATTEMPTS => {
+                    warn!(
+                        "pictures::upload photo_id: {}, attempt {}, {}",
+                        self.photo_id, attempt, e
+                    );
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("pictures::upload photo_id: {}, {}", self.photo_id, e);
+                    return Err(e);
+                }
+            }
         };
+        written.push(img_path.clone());
+        self.path = img_path;
+
+        // Recorded so the stored object can be verified later.
+        self.size = object.size as i64;
+        self.md5_hash = object.md5_hash.unwrap_or_default();
 
         debug!(
             "pictures::upload Picture uploaded to cloud storage - {} - {}",
             self.picture_date,
-            self.id.unwrap_or_default().to_hex()
+            id.to_hex()
         );
 
-        // Make Thumbnail
-        let thumb_bytes = match create_thumbnail(img_bytes.as_ref(), THUMB_WIDTH, THUMB_HEIGHT) {
-            Ok(t) => t,
-            Err(e) => {
-                error!("pictures::upload, error generating thumbnail, {:?}", e);
-                return Err(e);
+        // Read EXIF, and fall back to its capture time when the origin date was unusable.
+        self.apply_exif(metadata::read_exif(&img_bytes));
+        drop(img_bytes);
+
+        // A date corrected by EXIF files the picture elsewhere, the object is moved there.
+        self.location = camera.location_at(self.date);
+        let exif_path = options.path_template.render(self, Rendition::Full);
+        if exif_path != self.path {
+            debug!(
+                "pictures::upload photo_id: {}, moving {} to {} for the exif date",
+                self.photo_id, self.path, exif_path
+            );
+            written.push(exif_path.clone());
+            gcp_client
+                .move_object(&self.bucket, &self.path, &exif_path)
+                .await?;
+            written.retain(|p| *p != self.path);
+            self.path = exif_path;
+        }
+
+        // Sun and moon phase when the picture was taken.
+        self.apply_astronomy(camera.gps.as_ref());
+
+        // Weather at the camera when the picture was taken, it needs a gps fix.
+        if let (Some(w), Some(gps)) = (weather, &camera.gps) {
+            match w.observation(&self.camera_id, gps, self.date).await {
+                Ok(o) => self.apply_weather(&o),
+                Err(e) => warn!(
+                    "pictures::upload weather for photo_id: {}, {:?}",
+                    self.photo_id, e
+                ),
             }
+        }
+
+        // Day or night IR, before the thumbnail so night frames get their levels stretched.
+        self.apply_light(&img);
+
+        let img = match &options.detector {
+            Some(detector) => self.apply_detections(detector.clone(), img).await?,
+            None => img,
         };
 
-        let thumb_path = format!("{}/{}-thumb.jpg", base_path, id.to_hex());
+        // Empty frames are found by comparing with recent frames of the camera.
+        self.apply_empty(db, &img, options).await;
+        let empty_frames = options
+            .empty_frames
+            .for_detections(self.detections.as_ref());
+        if self.likely_empty && empty_frames != EmptyFrames::Keep {
+            // The picture is streamed before it can be looked at, so it's removed again.
+            gcp_client.delete(&self.bucket, &self.path).await?;
+            written.retain(|p| *p != self.path);
+            self.path = String::new();
+            self.size = 0;
+            self.md5_hash = String::new();
+
+            if empty_frames == EmptyFrames::Skip {
+                debug!(
+                    "pictures::upload photo_id: {} is likely empty, not stored",
+                    self.photo_id
+                );
+                return self.insert(db).await;
+            }
+        }
+
+        // Make Thumbnail
+        let thumb_bytes = match self.make_thumbnail(img, options) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("pictures::upload, error generating thumbnail, {:?}", e);
+                return Err(e);
+            }
+        };
+
+        let thumb_path = options.path_template.render(self, Rendition::Thumb);
 
         // Save Image to cloud storage
         if let Err(e) = gcp_client
             .save_to_bucket(
-                gcp_bucket.as_str(),
-                thumb_bytes.clone(),
+                self.bucket.as_str(),
+                thumb_bytes,
                 thumb_path.as_str(),
                 gdrive::MIME_JPEG,
             )
@@ -252,11 +1051,11 @@ impl Picture {
         debug!(
             "pictures::upload Picture thumbnail uploaded to cloud storage - {} - {}",
             self.picture_date,
-            self.id.unwrap_or_default().to_hex()
+            id.to_hex()
         );
 
         self.thumb_path.clone_from(&thumb_path);
-        self.location.clone_from(&camera_name);
+        written.push(thumb_path);
 
         // Save Picture to DB.
         if let Err(e) = self.insert(db).await {
@@ -270,10 +1069,646 @@ impl Picture {
 
         Ok(())
     }
+
+    /// Classifies the picture as day or night IR by its colors, cross-checked with the sun
+    /// phase in the weather data.
+    ///
+    /// Arguments:
+    ///
+    /// img: The decoded picture.
+    pub fn apply_light(&mut self, img: &DynamicImage) {
+        let sun_phase = self
+            .weather_data
+            .as_ref()
+            .map(|w| w.sun_phase.as_str())
+            .unwrap_or_default();
+
+        let (light, mismatch) = enhance::classify_with_sun(img, sun_phase);
+        if mismatch {
+            warn!(
+                "pictures::apply_light photo_id: {}, {:?} frame with sun phase {}",
+                self.photo_id, light, sun_phase
+            );
+        }
+
+        self.light = light;
+        self.light_mismatch = mismatch;
+    }
+
+    /// Marks the picture likely empty when it barely differs from a recent frame of the camera.
+    /// A detection by the model overrides the comparison. The signature of the picture is
+    /// stored for the frames that come after it.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// img: The decoded picture.
+    /// options: Upload options with the banner settings and the empty threshold.
+    pub async fn apply_empty(
+        &mut self,
+        db: &Database,
+        img: &DynamicImage,
+        options: &UploadOptions,
+    ) {
+        let signature = frame_signature(img, options.banner_ratio);
+
+        let references = match self.recent_signatures(db).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(
+                    "pictures::apply_empty photo_id: {}, unable to load recent frames, {:?}",
+                    self.photo_id, e
+                );
+                Vec::new()
+            }
+        };
+
+        let detected = self
+            .detections
+            .as_ref()
+            .is_some_and(|d| d.labels.iter().any(|l| l != detect::EMPTY));
+
+        self.likely_empty =
+            !detected && likely_empty(&signature, &references, options.empty_max_changed);
+        self.signature = Some(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: signature,
+        });
+    }
+
+    /// Returns the signatures of recent frames of the camera in the same light, taken before
+    /// the picture and outside its trigger.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    pub async fn recent_signatures(&self, db: &Database) -> crate::Result<Vec<Vec<u8>>> {
+        let before = DateTime::from_millis(
+            self.date.timestamp_millis() - EMPTY_REFERENCE_GAP_MINUTES * 60_000,
+        );
+
+        let coll: Collection<Document> = db.collection(COLLECTION);
+        let mut cursor = coll
+            .find(doc! {
+                "camera_id": &self.camera_id,
+                "light": bson::to_bson(&self.light)?,
+                "date": { "$lt": before.try_to_rfc3339_string()? },
+                "signature": { "$exists": true },
+            })
+            .sort(doc! { "date": -1 })
+            .limit(EMPTY_REFERENCE_FRAMES)
+            .projection(doc! { "signature": 1 })
+            .await?;
+
+        let mut signatures = Vec::new();
+        while cursor.advance().await? {
+            if let Ok(b) = cursor.current().get_binary("signature") {
+                signatures.push(b.bytes.to_vec());
+            }
+        }
+
+        Ok(signatures)
+    }
+
+    /// Runs the detection model on the picture on a blocking thread, inference would stall the
+    /// runtime. A failed detection is logged and the picture is stored without detections.
+    /// Returns the picture for the steps after detection.
+    ///
+    /// Arguments:
+    ///
+    /// detector: The detection model.
+    /// img: The decoded picture.
+    pub async fn apply_detections(
+        &mut self,
+        detector: Arc<dyn Detector>,
+        img: DynamicImage,
+    ) -> crate::Result<DynamicImage> {
+        let name = detector.name().to_string();
+        let (result, img) = tokio::task::spawn_blocking(move || {
+            let result = detector.detect(&img).map_err(|e| format!("{:?}", e));
+            (result, img)
+        })
+        .await?;
+
+        match result {
+            Ok(boxes) => {
+                let detections = Detections::new(&name, boxes);
+                debug!(
+                    "pictures::apply_detections photo_id: {}, {:?}",
+                    self.photo_id, detections.labels
+                );
+                self.detections = Some(detections);
+            }
+            Err(e) => warn!(
+                "pictures::apply_detections photo_id: {}, detection failed, {}",
+                self.photo_id, e
+            ),
+        }
+
+        Ok(img)
+    }
+
+    /// Returns the thumbnail of the picture, the info strip is processed first when the options
+    /// ask for it.
+    ///
+    /// Arguments:
+    ///
+    /// img: The decoded picture.
+    /// options: Processing done on the thumbnail.
+    pub fn make_thumbnail(
+        &mut self,
+        img: DynamicImage,
+        options: &UploadOptions,
+    ) -> crate::Result<Vec<u8>> {
+        let img = if options.read_banner || options.strip_banner {
+            self.process_banner(img, options)
+        } else {
+            img
+        };
+
+        let orientation = self.exif.as_ref().map_or(1, |e| e.orientation);
+        let thumb = enhance::derive(
+            &img,
+            orientation,
+            self.light,
+            THUMB_WIDTH,
+            THUMB_HEIGHT,
+            &options.enhance,
+        );
+
+        encode_jpeg(&thumb)
+    }
+
+    /// Replaces a picture stored in a smaller quality with a better rendition, when the
+    /// provider has one now. The rendition is validated in memory before the objects are
+    /// overwritten at their paths, and the thumbnail is regenerated. Returns whether the
+    /// picture was upgraded.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// client: Spypoint client used to download picture.
+    /// gcp_client: Google cloud storage client.
+    /// sources: The current renditions of the photo, the signed urls of the stored picture
+    /// expire.
+    /// quality: The quality wanted.
+    /// options: Processing done on the picture before it is saved.
+    pub async fn upgrade(
+        &mut self,
+        db: &Database,
+        client: &Client,
+        gcp_client: &GCPClient,
+        sources: Vec<PhotoSource>,
+        quality: Quality,
+        options: &UploadOptions,
+    ) -> crate::Result<bool> {
+        let stored = self.quality;
+
+        // Empty frames that weren't kept in full stay that way.
+        if self.path.is_empty() {
+            return Ok(false);
+        }
+
+        // Only renditions better than the stored one are tried, and not again right after a
+        // failed attempt.
+        self.sources = sources.into_iter().filter(|s| s.quality > stored).collect();
+        if quality <= stored || self.sources.is_empty() || !self.upgrade_due(DateTime::now()) {
+            return Ok(false);
+        }
+        self.quality = quality;
+
+        // Every failure of the rendition counts for the backoff.
+        let img = match self.replace_rendition(db, client, gcp_client).await {
+            Ok(img) => img,
+            Err(e) => {
+                self.quality = stored;
+                self.upgrade_attempts += 1;
+                self.upgrade_failed_at = Some(DateTime::now());
+                if let Err(e) = self.update_upgrade_attempts(db).await {
+                    error!(
+                        "pictures::upgrade photo_id: {}, unable to record the failed attempt, {:?}",
+                        self.photo_id, e
+                    );
+                }
+                return Err(e);
+            }
+        };
+
+        info!(
+            "pictures::upgrade photo_id: {} upgraded from {} to {}",
+            self.photo_id,
+            stored.as_str(),
+            self.quality.as_str()
+        );
+
+        self.apply_light(&img);
+        let thumb_bytes = self.make_thumbnail(img, options)?;
+        gcp_client
+            .save_to_bucket(
+                &self.bucket,
+                thumb_bytes,
+                &self.thumb_path,
+                gdrive::MIME_JPEG,
+            )
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Stores the rendition of the sources in place of the stored picture. It's streamed next to
+    /// the stored object and only moved over it once it is validated and its hash checked. The
+    /// backoff is reset with the rendition in the database. Returns the decoded picture.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB Database
+    /// client: Spypoint client used to download picture.
+    /// gcp_client: Google cloud storage client.
+    async fn replace_rendition(
+        &mut self,
+        db: &Database,
+        client: &Client,
+        gcp_client: &GCPClient,
+    ) -> crate::Result<DynamicImage> {
+        let upgrade_path = format!("{}.upgrade", self.path);
+        let (_, _, img) = self
+            .stream_to_bucket(client, gcp_client, &upgrade_path)
+            .await?;
+
+        let object = match gcp_client
+            .move_object(&self.bucket, &upgrade_path, &self.path)
+            .await
+        {
+            Ok(object) => object,
+            Err(e) => {
+                if let Err(e) = gcp_client.delete(&self.bucket, &upgrade_path).await {
+                    error!(
+                        "pictures::upgrade, unable to delete {}, {:?}",
+                        upgrade_path, e
+                    );
+                }
+                return Err(Box::from(e));
+            }
+        };
+
+        self.size = object.size as i64;
+        self.md5_hash = object.md5_hash.unwrap_or_default();
+        self.upgrade_attempts = 0;
+        self.upgrade_failed_at = None;
+        self.update_rendition(db).await?;
+
+        Ok(img)
+    }
+
+    /// Updates the stored rendition of a picture in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    pub async fn update_rendition(&self, db: &Database) -> crate::Result<()> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let filter = doc! {
+            "photo_id": &self.photo_id,
+        };
+        let update = doc! {
+            "$set": {
+                "quality": self.quality.as_str(),
+                "photo_url": &self.photo_url,
+                "size": self.size,
+                "md5_hash": &self.md5_hash,
+                "light": bson::to_bson(&self.light)?,
+                "light_mismatch": self.light_mismatch,
+                "upgrade_attempts": self.upgrade_attempts,
+                "upgrade_failed_at": self.upgrade_failed_at,
+            },
+        };
+
+        coll.update_one(filter, update).await?;
+        Ok(())
+    }
+
+    /// Updates the failed upgrade attempts of a picture in the database.
+    ///
+    /// Arguments:
+    ///
+    /// db: MongoDB database
+    pub async fn update_upgrade_attempts(&self, db: &Database) -> crate::Result<()> {
+        let coll: Collection<Picture> = db.collection(COLLECTION);
+        let filter = doc! {
+            "photo_id": &self.photo_id,
+        };
+        let update = doc! {
+            "$set": {
+                "upgrade_attempts": self.upgrade_attempts,
+                "upgrade_failed_at": self.upgrade_failed_at,
+            },
+        };
+
+        coll.update_one(filter, update).await?;
+        Ok(())
+    }
+
+    /// Returns whether an upgrade can be tried, false during the backoff after a failed
+    /// attempt.
+    ///
+    /// Arguments:
+    ///
+    /// now: The current time.
+    pub fn upgrade_due(&self, now: DateTime) -> bool {
+        let Some(failed_at) = self.upgrade_failed_at else {
+            return true;
+        };
+
+        let doublings = (self.upgrade_attempts - 1).clamp(0, 8) as u32;
+        let hours = (UPGRADE_BACKOFF_HOURS << doublings).min(UPGRADE_MAX_BACKOFF_HOURS);
+        now.timestamp_millis() - failed_at.timestamp_millis() >= hours * 3_600_000
+    }
+}
+
+/// Starts the download of a rendition, the verb and headers of the provider's signed url are
+/// applied.
+///
+/// Arguments:
+///
+/// client: The client provider from where to get the picture from.
+/// source: The rendition to download.
+async fn fetch(client: &Client, source: &PhotoSource) -> crate::Result<Response> {
+    let method = Method::from_bytes(source.verb.as_bytes()).unwrap_or(Method::GET);
+
+    let mut builder = client
+        .request(method, &source.url)
+        .timeout(Duration::from_secs(DOWNLOAD_TIMEOUT_SECS));
+    for h in &source.headers {
+        builder = builder.header(&h.name, &h.value);
+    }
+
+    let resp = builder.send().await?;
+    if !resp.status().is_success() {
+        return Err(Box::from(format!(
+            "pictures::download http_status {}, url: {}",
+            resp.status().as_u16(),
+            source.url
+        )));
+    }
+
+    Ok(resp)
+}
+
+/// Returns the signature of a frame, a small grayscale version without the info strip, whose
+/// timestamp and temperature change on every frame.
+///
+/// Arguments:
+///
+/// img: The decoded picture.
+/// banner_ratio: The height of the info strip as a ratio of the picture height.
+pub fn frame_signature(img: &DynamicImage, banner_ratio: f32) -> Vec<u8> {
+    let body = match banner::split_banner(img, banner_ratio) {
+        Some((body, _)) => body,
+        None => img.clone(),
+    };
+
+    body.thumbnail_exact(SIGNATURE_WIDTH, SIGNATURE_HEIGHT)
+        .to_luma8()
+        .into_raw()
+}
+
+/// Returns the share of the cells of two signatures that changed, after evening out the
+/// brightness of the frames. Signatures of different sizes are fully changed.
+///
+/// Arguments:
+///
+/// a: The signature of a frame.
+/// b: The signature of the other frame.
+pub fn changed_fraction(a: &[u8], b: &[u8]) -> f32 {
+    if a.is_empty() || a.len() != b.len() {
+        return 1.0;
+    }
+
+    let mean = |s: &[u8]| s.iter().map(|v| *v as i32).sum::<i32>() / s.len() as i32;
+    let offset = mean(a) - mean(b);
+
+    let changed = a
+        .iter()
+        .zip(b)
+        .filter(|(x, y)| (**x as i32 - **y as i32 - offset).abs() > CELL_CHANGE)
+        .count();
+
+    changed as f32 / a.len() as f32
+}
+
+/// Whether a frame barely differs from one of the reference frames. A frame without reference
+/// frames isn't empty.
+///
+/// Arguments:
+///
+/// signature: The signature of the frame.
+/// references: The signatures of recent frames of the camera.
+/// max_changed: The share of changed cells below which the frame is empty.
+pub fn likely_empty(signature: &[u8], references: &[Vec<u8>], max_changed: f32) -> bool {
+    references
+        .iter()
+        .map(|r| changed_fraction(signature, r))
+        .any(|c| c < max_changed)
+}
+
+/// Returns the content type of a response, None when it isn't set.
+fn content_type(resp: &Response) -> Option<&str> {
+    resp.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+}
+
+/// Checks that a download is an image. A missing or generic binary content type is allowed, the
+/// bytes are checked by validate_jpeg.
+///
+/// Arguments:
+///
+/// content_type: The content type of the download.
+pub fn check_content_type(content_type: Option<&str>) -> Result<(), InvalidImage> {
+    let Some(content_type) = content_type else {
+        return Ok(());
+    };
+
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    match mime.as_str() {
+        "" | "image/jpeg" | "image/jpg" | "application/octet-stream" | "binary/octet-stream" => {
+            Ok(())
+        }
+        _ => Err(InvalidImage {
+            reason: format!("content type {}", mime),
+        }),
+    }
+}
+
+/// Returns the decoded image when the bytes are a whole JPEG. The magic bytes, the end of image
+/// marker and a full decode are checked, an HTML error page or a truncated download fails.
+///
+/// Arguments:
+///
+/// bytes: The downloaded bytes.
+pub fn validate_jpeg(bytes: &[u8]) -> Result<DynamicImage, InvalidImage> {
+    if !bytes.starts_with(&JPEG_MAGIC) {
+        return Err(InvalidImage {
+            reason: String::from("no jpeg magic bytes"),
+        });
+    }
+
+    // Some encoders pad the file with zeros after the end of image marker.
+    let end = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    if !bytes[..end].ends_with(&JPEG_EOI) {
+        return Err(InvalidImage {
+            reason: String::from("no end of image marker, the download is truncated"),
+        });
+    }
+
+    image::load_from_memory_with_format(bytes, ImageFormat::Jpeg).map_err(|e| InvalidImage {
+        reason: format!("decode, {}", e),
+    })
+}
+
+/// Computes the sun and moon phase of every picture in the database, using the location of the
+/// camera that took it. Returns the number of pictures updated.
+///
+/// Arguments:
+///
+/// db: MongoDB Database
+pub async fn backfill_astronomy(db: &Database) -> crate::Result<i64> {
+    let cameras = Camera::all(db).await?;
+
+    let coll: Collection<Picture> = db.collection(COLLECTION);
+    let mut cursor = coll.find(doc! {}).await?;
+    let mut updated = 0;
+
+    while cursor.advance().await? {
+        let mut picture = match cursor.deserialize_current() {
+            Ok(p) => p,
+            Err(e) => {
+                error!(
+                    "pictures::backfill_astronomy, unable to read picture, {:?}",
+                    e
+                );
+                continue;
+            }
+        };
+
+        let Some(camera) = cameras.iter().find(|c| c.camera_id == picture.camera_id) else {
+            debug!(
+                "pictures::backfill_astronomy no camera for photo_id: {}",
+                picture.photo_id
+            );
+            continue;
+        };
+
+        picture.apply_astronomy(camera.gps.as_ref());
+        picture.update_weather(db).await?;
+        updated += 1;
+    }
+
+    Ok(updated)
 }
 
-const THUMB_WIDTH: u32 = 400;
-const THUMB_HEIGHT: u32 = 400;
+/// The result of moving the picture objects to a new layout.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Pictures whose objects were moved.
+    pub moved: i64,
+    /// Pictures with an object that couldn't be moved, they're left at their current paths.
+    pub failed: i64,
+}
+
+/// Moves the objects of every picture in the database to the paths of the template and
+/// updates the paths of the picture. A picture whose object can't be moved is logged and
+/// counted, and the migration continues with the next picture.
+///
+/// Arguments:
+///
+/// db: MongoDB Database
+/// gcp_client: Google cloud storage client.
+/// template: The new layout of the picture objects.
+pub async fn migrate_paths(
+    db: &Database,
+    gcp_client: &GCPClient,
+    template: &PathTemplate,
+) -> crate::Result<MigrationReport> {
+    let coll: Collection<Picture> = db.collection(COLLECTION);
+    let mut cursor = coll.find(doc! {}).await?;
+    let mut report = MigrationReport::default();
+
+    while cursor.advance().await? {
+        let mut picture: Picture = match cursor.deserialize_current() {
+            Ok(p) => p,
+            Err(e) => {
+                error!("pictures::migrate_paths, unable to read picture, {:?}", e);
+                continue;
+            }
+        };
+
+        // Empty frames may have no objects.
+        let path = template.render(&picture, Rendition::Full);
+        let thumb_path = template.render(&picture, Rendition::Thumb);
+        let move_full = !picture.path.is_empty() && picture.path != path;
+        let move_thumb = !picture.thumb_path.is_empty() && picture.thumb_path != thumb_path;
+        if !move_full && !move_thumb {
+            continue;
+        }
+
+        debug!(
+            "pictures::migrate_paths moving {} to {}",
+            picture.path, path
+        );
+
+        let (path, thumb_path) = (move_full.then_some(path), move_thumb.then_some(thumb_path));
+        match move_picture(db, gcp_client, &mut picture, path, thumb_path).await {
+            Ok(()) => report.moved += 1,
+            Err(e) => {
+                error!(
+                    "pictures::migrate_paths, unable to move the objects of picture {:?}, {:?}",
+                    picture.id, e
+                );
+                report.failed += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Moves the objects of a picture to new paths. The paths are saved after each move, so a
+/// failed run can be resumed.
+async fn move_picture(
+    db: &Database,
+    gcp_client: &GCPClient,
+    picture: &mut Picture,
+    path: Option<String>,
+    thumb_path: Option<String>,
+) -> crate::Result<()> {
+    if let Some(path) = path {
+        gcp_client
+            .move_object(&picture.bucket, &picture.path, &path)
+            .await?;
+        picture.path = path;
+        picture.update_paths(db).await?;
+    }
+
+    if let Some(thumb_path) = thumb_path {
+        gcp_client
+            .move_object(&picture.bucket, &picture.thumb_path, &thumb_path)
+            .await?;
+        picture.thumb_path = thumb_path;
+        picture.update_paths(db).await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) const THUMB_WIDTH: u32 = 400;
+pub(crate) const THUMB_HEIGHT: u32 = 400;
 
 /// Resizes the image represented by the bytes parameters to the size (width and height) parameters.
 pub fn create_thumbnail(bytes: &[u8], width: u32, height: u32) -> crate::Result<Vec<u8>> {
@@ -288,12 +1723,21 @@ pub fn create_thumbnail(bytes: &[u8], width: u32, height: u32) -> crate::Result<
         }
     };
 
+    thumbnail_from_image(&img, width, height)
+}
+
+/// Resizes a decoded image to the size (width and height) parameters and encodes it as a jpeg.
+pub fn thumbnail_from_image(img: &DynamicImage, width: u32, height: u32) -> crate::Result<Vec<u8>> {
     let thumb = img.resize(width, height, FilterType::Triangle);
+    encode_jpeg(&thumb)
+}
 
+/// Returns the image encoded as a jpeg.
+pub fn encode_jpeg(img: &DynamicImage) -> crate::Result<Vec<u8>> {
     let mut cursor = Cursor::new(Vec::new());
     let encoder = JpegEncoder::new_with_quality(&mut cursor, 95);
 
-    thumb.write_with_encoder(encoder)?;
+    img.write_with_encoder(encoder)?;
     Ok(cursor.into_inner())
 }
 
@@ -313,7 +1757,19 @@ mod tests {
     use std::fs::File;
     use std::io::{BufReader, Read, Write};
 
-    use crate::cameras::pictures::{basic_thumbnail, create_thumbnail, THUMB_HEIGHT, THUMB_WIDTH};
+    use httpmock::prelude::*;
+    use image::{DynamicImage, Rgb, RgbImage};
+    use mongodb::bson;
+    use mongodb::bson::DateTime;
+
+    use crate::cameras::banner::BANNER_HEIGHT_RATIO;
+    use crate::cameras::detect::{BoundingBox, Detection, Detections};
+    use crate::cameras::metadata::Exif;
+    use crate::cameras::pictures::{
+        basic_thumbnail, check_content_type, create_thumbnail, frame_signature, likely_empty,
+        validate_jpeg, EmptyFrames, Picture, Quality, EMPTY_MAX_CHANGED, THUMB_HEIGHT, THUMB_WIDTH,
+    };
+    use crate::spypoint::{Hd, Header, Photo};
 
     #[test]
     fn basic_create_thumbnail() {
@@ -337,4 +1793,248 @@ mod tests {
         let mut file = File::create("thumb_black.jpg").expect("File to be created");
         file.write_all(&bytes).expect("Thumbnail Image to be saved");
     }
+
+    #[test]
+    fn validate_download() {
+        let jpeg = basic_thumbnail(THUMB_WIDTH, THUMB_HEIGHT).expect("Black Thumbnail");
+        assert!(validate_jpeg(&jpeg).is_ok());
+
+        let mut padded = jpeg.clone();
+        padded.extend_from_slice(&[0, 0, 0]);
+        assert!(validate_jpeg(&padded).is_ok());
+
+        assert!(validate_jpeg(b"<html>Access Denied</html>").is_err());
+        assert!(validate_jpeg(&jpeg[..jpeg.len() / 2]).is_err());
+        assert!(validate_jpeg(&[]).is_err());
+
+        assert!(check_content_type(Some("image/jpeg")).is_ok());
+        assert!(check_content_type(Some("binary/octet-stream")).is_ok());
+        assert!(check_content_type(None).is_ok());
+        assert!(check_content_type(Some("text/html; charset=utf-8")).is_err());
+    }
+
+    #[test]
+    fn download_error_status() {
+        let mock_server = MockServer::start();
+        let photo_mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/PICT0004.jpg");
+            then.status(404).body("<html>Not Found</html>");
+        });
+
+        let mut picture = Picture::from(Photo::default());
+        picture.photo_url = format!("http://{}/PICT0004.jpg", mock_server.address());
+        picture.sources.clear();
+
+        tokio_test::block_on(async {
+            let result = picture.download(&reqwest::Client::new()).await;
+
+            photo_mock.assert();
+            assert!(result.is_err());
+        });
+    }
+
+    #[test]
+    fn download_fallback() {
+        let mock_server = MockServer::start();
+        let large_mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/PICT0004_L.jpg");
+            then.status(404).body("<html>Not Found</html>");
+        });
+        let medium_mock = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/PICT0004_M.jpg")
+                .header("x-amz-security-token", "token");
+            then.status(200).body("jpeg");
+        });
+
+        let photo = Photo {
+            large: Hd {
+                path: String::from("PICT0004_L.jpg"),
+                host: mock_server.address().to_string(),
+                ..Default::default()
+            },
+            medium: Hd {
+                verb: String::from("GET"),
+                path: String::from("PICT0004_M.jpg"),
+                host: mock_server.address().to_string(),
+                headers: vec![Header {
+                    name: String::from("x-amz-security-token"),
+                    value: String::from("token"),
+                }],
+            },
+            ..Default::default()
+        };
+
+        let mut picture = Picture::from_photo(photo, Quality::Large);
+        assert_eq!(picture.sources.len(), 3);
+        assert!(picture.photo_url.ends_with("/PICT0004_L.jpg"));
+
+        // The mock server doesn't speak https.
+        for source in picture.sources.iter_mut() {
+            source.url = source.url.replace("https://", "http://");
+        }
+
+        tokio_test::block_on(async {
+            let result = picture.download(&reqwest::Client::new()).await;
+
+            large_mock.assert();
+            medium_mock.assert();
+            assert!(result.is_ok());
+        });
+
+        assert_eq!(picture.quality, Quality::Medium);
+        assert!(picture.photo_url.ends_with("/PICT0004_M.jpg"));
+        assert_eq!(picture.photo_headers.len(), 1);
+    }
+
+    #[test]
+    fn empty_frames() {
+        let background = RgbImage::from_fn(320, 240, |x, y| {
+            let v = ((x + y) % 200) as u8;
+            Rgb([v, v / 2 + 40, 60])
+        });
+        let signature = frame_signature(
+            &DynamicImage::ImageRgb8(background.clone()),
+            BANNER_HEIGHT_RATIO,
+        );
+        assert_eq!(signature.len(), 32 * 24);
+
+        // The same scene a little brighter, e.g. a cloud moved.
+        let mut brighter = background.clone();
+        for p in brighter.pixels_mut() {
+            *p = Rgb([p[0] + 15, p[1] + 15, p[2] + 15]);
+        }
+        let brighter = frame_signature(&DynamicImage::ImageRgb8(brighter), BANNER_HEIGHT_RATIO);
+        assert!(likely_empty(
+            &brighter,
+            std::slice::from_ref(&signature),
+            EMPTY_MAX_CHANGED
+        ));
+
+        // An animal in the frame.
+        let mut animal = background.clone();
+        for x in 120..200 {
+            for y in 80..160 {
+                animal.put_pixel(x, y, Rgb([255, 255, 255]));
+            }
+        }
+        let animal = frame_signature(&DynamicImage::ImageRgb8(animal), BANNER_HEIGHT_RATIO);
+        assert!(!likely_empty(
+            &animal,
+            std::slice::from_ref(&signature),
+            EMPTY_MAX_CHANGED
+        ));
+
+        // A small animal changes a few cells, it takes a lower threshold.
+        let mut small = background;
+        for x in 150..166 {
+            for y in 100..116 {
+                small.put_pixel(x, y, Rgb([255, 255, 255]));
+            }
+        }
+        let small = frame_signature(&DynamicImage::ImageRgb8(small), BANNER_HEIGHT_RATIO);
+        assert!(likely_empty(
+            &small,
+            std::slice::from_ref(&signature),
+            EMPTY_MAX_CHANGED
+        ));
+        assert!(!likely_empty(&small, std::slice::from_ref(&signature), 0.001));
+
+        // Nothing to compare with.
+        assert!(!likely_empty(&signature, &[], EMPTY_MAX_CHANGED));
+    }
+
+    #[test]
+    fn empty_frames_skip() {
+        let empty = Detections::new("md_v5a", Vec::new());
+        let deer = Detections::new(
+            "species",
+            vec![Detection {
+                label: String::from("deer"),
+                confidence: 0.3,
+                bbox: BoundingBox::default(),
+            }],
+        );
+
+        // Only a frame the model found empty is skipped.
+        assert_eq!(
+            EmptyFrames::Skip.for_detections(Some(&empty)),
+            EmptyFrames::Skip
+        );
+        assert_eq!(
+            EmptyFrames::Skip.for_detections(None),
+            EmptyFrames::Thumbnail
+        );
+        assert_eq!(
+            EmptyFrames::Skip.for_detections(Some(&deer)),
+            EmptyFrames::Thumbnail
+        );
+        assert_eq!(EmptyFrames::Keep.for_detections(None), EmptyFrames::Keep);
+        assert_eq!(
+            EmptyFrames::Thumbnail.for_detections(Some(&empty)),
+            EmptyFrames::Thumbnail
+        );
+    }
+
+    #[test]
+    fn exif_capture_time_fallback() {
+        let mut picture = Picture::from(Photo::default());
+        let exif = Exif {
+            capture_time: String::from("2024:07:17 19:51:41"),
+            ..Default::default()
+        };
+
+        picture.apply_exif(Some(exif));
+
+        assert!(picture.exif.is_some());
+        assert_eq!(
+            picture.date.try_to_rfc3339_string().unwrap(),
+            "2024-07-17T19:51:41Z"
+        );
+    }
+
+    #[test]
+    fn signed_sources_not_stored() {
+        let photo = Photo {
+            large: Hd {
+                path: String::from("PICT0004_L.jpg"),
+                host: String::from("s3.amazonaws.com"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let picture = Picture::from_photo(photo, Quality::Large);
+        assert!(!picture.photo_url.is_empty());
+
+        let document = bson::to_document(&picture).unwrap();
+        for key in ["photo_verb", "photo_headers", "sources"] {
+            assert!(!document.contains_key(key), "{} is stored", key);
+        }
+        assert_eq!(document.get_str("photo_url").unwrap(), picture.photo_url);
+
+        let stored: Picture = bson::from_document(document).unwrap();
+        assert!(stored.sources.is_empty());
+    }
+
+    #[test]
+    fn upgrade_backoff() {
+        let mut picture = Picture::from(Photo::default());
+        let failed_at = DateTime::from_millis(1_720_000_000_000);
+        let hours = |h: i64| DateTime::from_millis(failed_at.timestamp_millis() + h * 3_600_000);
+        assert!(picture.upgrade_due(failed_at));
+
+        picture.upgrade_attempts = 1;
+        picture.upgrade_failed_at = Some(failed_at);
+        assert!(!picture.upgrade_due(hours(5)));
+        assert!(picture.upgrade_due(hours(6)));
+
+        picture.upgrade_attempts = 3;
+        assert!(!picture.upgrade_due(hours(23)));
+        assert!(picture.upgrade_due(hours(24)));
+
+        // The backoff stops growing at a week.
+        picture.upgrade_attempts = 20;
+        assert!(!picture.upgrade_due(hours(167)));
+        assert!(picture.upgrade_due(hours(168)));
+    }
 }
@@ -0,0 +1,364 @@
+use chrono::Months;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::cameras::Subscription;
+use crate::spypoint;
+use crate::spypoint::ConfigUpdate;
+
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// Trigger delays in the order the camera offers them, a recommendation moves one step up.
+pub const DELAYS: [&str; 8] = [
+    "10s", "30s", "1min", "2min", "5min", "10min", "15min", "30min",
+];
+
+/// The DELAYS in seconds, for the models that report motionDelay.
+pub const MOTION_DELAYS: [i64; 8] = [10, 30, 60, 120, 300, 600, 900, 1800];
+
+/// Usage of the monthly photo quota of a camera.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuotaUsage {
+    /// Photos transmitted this month.
+    pub used: i64,
+    /// Photos included per month.
+    pub quota: i64,
+    /// Photos expected by the end of the month at the current rate.
+    pub projected: i64,
+    /// End of the quota month.
+    pub month_end: DateTime,
+}
+
+impl QuotaUsage {
+    /// Projects the photos a camera transmits by the end of the quota month, from the photos
+    /// transmitted so far. None when the plan is unlimited or the billing dates are unknown.
+    ///
+    /// Arguments:
+    ///
+    /// sub: The subscription of the camera.
+    /// now: The current time.
+    pub fn from_subscription(sub: &Subscription, now: DateTime) -> Option<Self> {
+        if sub.photo_count_per_month <= 0 {
+            return None;
+        }
+
+        // The quota resets monthly, also on plans billed yearly.
+        let end = sub.month_end_billing_cycle.or(sub.end_date_billing_cycle)?;
+        let mut start = end.to_chrono().checked_sub_months(Months::new(1))?;
+        if let Some(s) = sub.start_date_billing_cycle {
+            start = start.max(s.to_chrono());
+        }
+
+        let total = (end.to_chrono() - start).num_milliseconds() as f64;
+        if total <= 0.0 {
+            return None;
+        }
+
+        // The first hours of a month would project wildly, so at least a day is counted.
+        let elapsed = ((now.to_chrono() - start).num_milliseconds() as f64)
+            .clamp(MILLIS_PER_DAY.min(total), total);
+        let projected = (sub.photo_count as f64 * total / elapsed).round() as i64;
+
+        Some(QuotaUsage {
+            used: sub.photo_count,
+            quota: sub.photo_count_per_month,
+            projected,
+            month_end: end,
+        })
+    }
+
+    /// Projects the photos by the end of the month from the rate since a config change was
+    /// applied, so the change is judged by the photos taken with it. None until a day has passed
+    /// since the change.
+    ///
+    /// Arguments:
+    ///
+    /// adjustment: The config change applied this quota month.
+    /// now: The current time.
+    pub fn since_adjustment(&self, adjustment: &QuotaAdjustment, now: DateTime) -> Option<Self> {
+        let elapsed = (now.timestamp_millis() - adjustment.applied.timestamp_millis()) as f64;
+        if elapsed < MILLIS_PER_DAY {
+            return None;
+        }
+
+        let remaining = (self.month_end.timestamp_millis() - now.timestamp_millis()).max(0) as f64;
+        let rate = (self.used - adjustment.used).max(0) as f64 / elapsed;
+
+        Some(QuotaUsage {
+            projected: self.used + (rate * remaining).round() as i64,
+            ..self.clone()
+        })
+    }
+
+    /// Whether the camera runs out of photos before the end of the month at the current rate.
+    pub fn exceeds_quota(&self) -> bool {
+        self.projected > self.quota
+    }
+}
+
+/// A config change sent to a camera to keep it within its quota.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuotaAdjustment {
+    pub update: ConfigUpdate,
+    /// When the change was sent.
+    pub applied: DateTime,
+    /// End of the quota month the change was sent in.
+    pub month_end: DateTime,
+    /// Photos transmitted this month when the change was sent.
+    pub used: i64,
+}
+
+impl QuotaAdjustment {
+    /// Arguments:
+    ///
+    /// update: The config change sent.
+    /// usage: The quota usage the change was recommended for.
+    /// now: The time the change was sent.
+    pub fn new(update: ConfigUpdate, usage: &QuotaUsage, now: DateTime) -> Self {
+        QuotaAdjustment {
+            update,
+            applied: now,
+            month_end: usage.month_end,
+            used: usage.used,
+        }
+    }
+
+    /// Whether the camera reports the config of the change, it's applied on the next
+    /// transmission.
+    ///
+    /// Arguments:
+    ///
+    /// config: The current configuration of the camera.
+    pub fn in_effect(&self, config: &spypoint::Config) -> bool {
+        let update = &self.update;
+        update.multi_shot.iter().all(|m| config.multi_shot == *m)
+            && update.delay.iter().all(|d| config.delay == *d)
+            && update
+                .motion_delay
+                .iter()
+                .all(|d| config.motion_delay == Some(*d))
+    }
+}
+
+/// Returns the config change that keeps a camera within its quota, None when the projection
+/// is within the quota or there's nothing left to change. Fewer shots per trigger are
+/// recommended first, then a longer delay between triggers.
+///
+/// After a change was applied this quota month nothing more is recommended until the camera
+/// reports it, and then the projection is made from the photos taken since the change. So a
+/// change isn't escalated on the photos taken before it.
+///
+/// Arguments:
+///
+/// config: The current configuration of the camera.
+/// usage: The quota usage of the camera.
+/// last: The last change applied to the camera, None when there's none.
+/// now: The current time.
+pub fn recommend(
+    config: &spypoint::Config,
+    usage: &QuotaUsage,
+    last: Option<&QuotaAdjustment>,
+    now: DateTime,
+) -> Option<ConfigUpdate> {
+    let usage = match last.filter(|a| a.month_end == usage.month_end) {
+        Some(a) if !a.in_effect(config) => return None,
+        Some(a) => usage.since_adjustment(a, now)?,
+        None => usage.clone(),
+    };
+
+    if !usage.exceeds_quota() {
+        return None;
+    }
+
+    if config.multi_shot > 1 {
+        // Photos drop in proportion to the shots per trigger.
+        let shots = config.multi_shot as f64 * usage.quota as f64 / usage.projected as f64;
+        return Some(ConfigUpdate {
+            multi_shot: Some((shots.floor() as i64).max(1)),
+            ..Default::default()
+        });
+    }
+
+    if config.delay.is_empty() {
+        let seconds = config.motion_delay?;
+        let next = MOTION_DELAYS.iter().find(|d| **d > seconds)?;
+
+        return Some(ConfigUpdate {
+            motion_delay: Some(*next),
+            ..Default::default()
+        });
+    }
+
+    let i = DELAYS.iter().position(|d| *d == config.delay)?;
+    let next = DELAYS.get(i + 1)?;
+
+    Some(ConfigUpdate {
+        delay: Some(next.to_string()),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use mongodb::bson::DateTime;
+
+    use crate::cameras::quota::{recommend, QuotaAdjustment, QuotaUsage};
+    use crate::cameras::tests::{camera, SPY_CAMERA_JSON};
+    use crate::client::Server;
+    use crate::spypoint::PATH_CAMERA_CONFIG;
+    use crate::{client, spypoint};
+
+    /// Returns the time a number of days into the billing cycle of the test camera.
+    fn day(days: f64) -> DateTime {
+        let sub = camera().subscription.expect("subscription");
+        let start = sub.start_date_billing_cycle.unwrap().timestamp_millis();
+        DateTime::from_millis(start + (days * 86_400_000.0) as i64)
+    }
+
+    fn usage(photo_count: i64, days: i64) -> QuotaUsage {
+        let mut sub = camera().subscription.expect("subscription");
+        sub.photo_count = photo_count;
+
+        QuotaUsage::from_subscription(&sub, day(days as f64)).expect("quota usage")
+    }
+
+    #[test]
+    fn projection() {
+        // 31 day month.
+        let u = usage(4, 2);
+        assert_eq!(u.projected, 62);
+        assert!(!u.exceeds_quota());
+
+        let u = usage(10, 2);
+        assert_eq!(u.projected, 155);
+        assert!(u.exceeds_quota());
+
+        // Right after the reset a day is counted.
+        let u = usage(3, 0);
+        assert_eq!(u.projected, 93);
+    }
+
+    #[test]
+    fn unlimited_plan() {
+        let mut sub = camera().subscription.expect("subscription");
+        sub.photo_count_per_month = 0;
+        assert!(QuotaUsage::from_subscription(&sub, DateTime::now()).is_none());
+    }
+
+    #[test]
+    fn recommendation() {
+        let mut config = spypoint::Config {
+            multi_shot: 3,
+            delay: String::from("1min"),
+            ..Default::default()
+        };
+
+        assert!(recommend(&config, &usage(4, 2), None, day(2.0)).is_none());
+
+        let u = usage(10, 2);
+        let r = recommend(&config, &u, None, day(2.0)).expect("fewer shots");
+        assert_eq!(r.multi_shot, Some(1));
+        assert!(r.delay.is_none());
+
+        config.multi_shot = 1;
+        let r = recommend(&config, &u, None, day(2.0)).expect("longer delay");
+        assert_eq!(r.delay.as_deref(), Some("2min"));
+
+        config.delay = String::from("30min");
+        assert!(recommend(&config, &u, None, day(2.0)).is_none());
+
+        // Cameras that report motionDelay.
+        config.delay = String::new();
+        config.motion_delay = Some(60);
+        let r = recommend(&config, &u, None, day(2.0)).expect("longer motion delay");
+        assert_eq!(r.motion_delay, Some(120));
+        assert!(r.delay.is_none());
+    }
+
+    #[test]
+    fn adjustment_not_escalated() {
+        let mut config = spypoint::Config {
+            multi_shot: 1,
+            delay: String::from("1min"),
+            ..Default::default()
+        };
+
+        let u = usage(10, 2);
+        let update = recommend(&config, &u, None, day(2.0)).expect("longer delay");
+        let adjustment = QuotaAdjustment::new(update, &u, day(2.0));
+
+        // The camera hasn't applied the change yet, the month still projects over the quota.
+        assert!(!adjustment.in_effect(&config));
+        assert!(recommend(&config, &usage(14, 4), Some(&adjustment), day(4.0)).is_none());
+
+        // Applied, but less than a day of photos since.
+        config.delay = String::from("2min");
+        assert!(adjustment.in_effect(&config));
+        assert!(recommend(&config, &usage(11, 2), Some(&adjustment), day(2.5)).is_none());
+
+        // 2 photos a day since the change keep the camera within its quota, although the
+        // month projects 109 photos.
+        assert!(usage(14, 4).exceeds_quota());
+        assert!(recommend(&config, &usage(14, 4), Some(&adjustment), day(4.0)).is_none());
+
+        // 10 photos a day since the change, the next step.
+        let r = recommend(&config, &usage(30, 4), Some(&adjustment), day(4.0)).expect("5min");
+        assert_eq!(r.delay.as_deref(), Some("5min"));
+
+        // A change of a previous quota month is ignored.
+        let mut old = adjustment.clone();
+        old.month_end = day(0.0);
+        config.delay = String::from("1min");
+        let r = recommend(&config, &u, Some(&old), day(2.0)).expect("longer delay");
+        assert_eq!(r.delay.as_deref(), Some("2min"));
+    }
+
+    #[test]
+    fn config_request() {
+        let raw: serde_json::Value = serde_json::from_str(SPY_CAMERA_JSON).unwrap();
+        let sp_camera: spypoint::Camera = serde_json::from_value(raw.clone()).unwrap();
+
+        let update = recommend(&sp_camera.config, &usage(10, 2), None, day(2.0))
+            .expect("longer motion delay");
+        let body = serde_json::to_value(&update).unwrap();
+        assert_eq!(body, serde_json::json!({ "motionDelay": 120 }));
+
+        // Only fields the camera reports are sent, with the type the camera reports them in.
+        for (key, value) in body.as_object().unwrap() {
+            let current = &raw["config"][key];
+            assert!(!current.is_null(), "{} is a config field", key);
+            assert_eq!(
+                std::mem::discriminant(current),
+                std::mem::discriminant(value),
+                "{} type",
+                key
+            );
+        }
+
+        let mock_server = MockServer::start();
+        let path = format!("{}{}", PATH_CAMERA_CONFIG, sp_camera.id);
+        let config_mock = mock_server.mock(|when, then| {
+            when.method(PUT)
+                .path(path)
+                .header("Authorization", "Bearer token")
+                .json_body(body.clone());
+            then.status(200).body("{}");
+        });
+
+        let server = Server {
+            user_name: String::from("ed"),
+            password: String::from("money"),
+            host: format!("http://{}", mock_server.address()),
+        };
+        let client = client::Client::new(server).expect("spypoint client");
+        client.set_auth(String::from("token"));
+
+        tokio_test::block_on(async {
+            spypoint::update_config(&client, &sp_camera.id, &update)
+                .await
+                .expect("config updated");
+            config_mock.assert();
+        });
+    }
+}
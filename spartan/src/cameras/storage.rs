@@ -0,0 +1,424 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Duration, Utc};
+use cloud_storage::Object;
+use image::ImageFormat;
+use log::{debug, error, info, warn};
+use mongodb::bson::oid::ObjectId;
+use mongodb::Database;
+
+use crate::cameras::paths::{PathTemplate, Rendition};
+use crate::cameras::pictures::{Picture, UploadOptions};
+use crate::sys::gdrive;
+use crate::sys::gdrive::GCPClient;
+
+/// Objects younger than this may belong to an upload in progress and are left alone.
+pub const ORPHAN_MIN_AGE_HOURS: i64 = 6;
+
+/// The result of reconciling the bucket with the pictures collection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconcileReport {
+    /// Objects under the picture prefix.
+    pub objects: i64,
+    /// Objects without a picture.
+    pub orphans: i64,
+    /// Orphans that were the missing object of their picture and were registered on it.
+    pub registered: i64,
+    /// Orphans that don't match the template, they're never deleted.
+    pub unknown: i64,
+    pub deleted: i64,
+}
+
+/// Returns the picture id and rendition of an object made by the template, None when the path
+/// doesn't match the template or has no picture id.
+///
+/// Arguments:
+///
+/// template: The layout of the picture objects.
+/// path: The object path.
+pub fn object_picture(template: &PathTemplate, path: &str) -> Option<(ObjectId, Rendition)> {
+    let values = template.parse(path)?;
+    let id = ObjectId::parse_str(values.get("id")?).ok()?;
+    let rendition = match values.get("rendition")?.as_str() {
+        "full" => Rendition::Full,
+        "thumb" => Rendition::Thumb,
+        _ => return None,
+    };
+
+    Some((id, rendition))
+}
+
+/// Checks orphans can be deleted with the template. A template that starts with a placeholder
+/// has no prefix, the whole bucket is listed and objects that aren't pictures could match it.
+///
+/// Arguments:
+///
+/// template: The layout of the picture objects.
+pub fn check_delete(template: &PathTemplate) -> crate::Result<()> {
+    if template.prefix().is_empty() {
+        return Err(Box::from(format!(
+            "storage::reconcile won't delete with template {:?}, it has no fixed prefix",
+            template
+        )));
+    }
+
+    Ok(())
+}
+
+/// Lists the objects under the picture prefix of the bucket and finds those without a
+/// picture. An orphan is registered on its picture when the picture's own object for that
+/// rendition is missing. When `delete` is set the other orphans that match the template are
+/// deleted, objects that don't are only reported. Deleting needs a template with a fixed
+/// prefix, see check_delete.
+///
+/// Arguments:
+///
+/// db: MongoDB Database
+/// gcp_client: Google cloud storage client.
+/// bucket: The name of the bucket.
+/// template: The layout of the picture objects.
+/// delete: Deletes the orphans, otherwise they're only reported.
+pub async fn reconcile(
+    db: &Database,
+    gcp_client: &GCPClient,
+    bucket: &str,
+    template: &PathTemplate,
+    delete: bool,
+) -> crate::Result<ReconcileReport> {
+    if delete {
+        check_delete(template)?;
+    }
+
+    let objects = gcp_client.list(bucket, template.prefix()).await?;
+    let names: HashSet<&str> = objects.iter().map(|o| o.name.as_str()).collect();
+    let known = Picture::stored_paths(db, bucket).await?;
+    let cutoff = Utc::now() - Duration::hours(ORPHAN_MIN_AGE_HOURS);
+
+    let mut report = ReconcileReport {
+        objects: objects.len() as i64,
+        ..Default::default()
+    };
+
+    for object in &objects {
+        if known.contains(&object.name) || object.time_created > cutoff {
+            continue;
+        }
+        report.orphans += 1;
+
+        let Some((id, rendition)) = object_picture(template, &object.name) else {
+            info!("storage::reconcile unknown object {}", object.name);
+            report.unknown += 1;
+            continue;
+        };
+
+        if let Some(mut picture) = Picture::find_by_id(db, id).await? {
+            let current = match rendition {
+                Rendition::Full => &mut picture.path,
+                Rendition::Thumb => &mut picture.thumb_path,
+            };
+
+            if !names.contains(&current.as_str()) {
+                info!(
+                    "storage::reconcile registering {} on picture {}",
+                    object.name, id
+                );
+                current.clone_from(&object.name);
+                picture.update_paths(db).await?;
+                report.registered += 1;
+                continue;
+            }
+        }
+
+        if !delete {
+            info!("storage::reconcile orphan {}", object.name);
+            continue;
+        }
+
+        debug!("storage::reconcile deleting {}", object.name);
+        gcp_client.delete(bucket, &object.name).await?;
+        report.deleted += 1;
+    }
+
+    Ok(report)
+}
+
+/// Size and hash of an object in the bucket.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoredObject {
+    pub size: i64,
+    /// Base64 MD5 hash, empty when not reported.
+    pub md5_hash: String,
+}
+
+impl From<&Object> for StoredObject {
+    fn from(value: &Object) -> Self {
+        StoredObject {
+            size: value.size as i64,
+            md5_hash: value.md5_hash.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// A problem with the stored objects of a picture.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    Missing(Rendition),
+    SizeMismatch { expected: i64, actual: i64 },
+    HashMismatch,
+}
+
+/// The result of verifying the pictures collection against the bucket.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    pub pictures: i64,
+    pub missing: i64,
+    pub size_mismatches: i64,
+    pub hash_mismatches: i64,
+    /// Missing thumbnails regenerated from the original.
+    pub repaired: i64,
+    /// Pictures whose objects aren't under the prefix of the path template, they aren't
+    /// checked.
+    pub outside_prefix: i64,
+}
+
+/// Returns the problems of the stored objects of a picture. The size and hash are only checked
+/// when they were recorded on upload, and objects of empty frames that weren't kept aren't
+/// expected.
+///
+/// Arguments:
+///
+/// picture: The picture to check.
+/// objects: The objects of the picture's bucket by path.
+pub fn check(picture: &Picture, objects: &HashMap<String, StoredObject>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    match objects.get(&picture.path) {
+        None if picture.path.is_empty() => {}
+        Some(o) => {
+            if picture.size > 0 && o.size != picture.size {
+                problems.push(Problem::SizeMismatch {
+                    expected: picture.size,
+                    actual: o.size,
+                });
+            }
+            if !picture.md5_hash.is_empty()
+                && !o.md5_hash.is_empty()
+                && o.md5_hash != picture.md5_hash
+            {
+                problems.push(Problem::HashMismatch);
+            }
+        }
+        None => problems.push(Problem::Missing(Rendition::Full)),
+    }
+
+    if !picture.thumb_path.is_empty() && !objects.contains_key(&picture.thumb_path) {
+        problems.push(Problem::Missing(Rendition::Thumb));
+    }
+
+    problems
+}
+
+/// Checks that the objects of every picture exist in the bucket with the recorded size and
+/// hash. Only the objects under the prefix of the path template are listed, as in reconcile.
+/// Missing thumbnails are regenerated from the original when `repair` is set, the way they are
+/// made on upload.
+///
+/// Arguments:
+///
+/// db: MongoDB Database
+/// gcp_client: Google cloud storage client.
+/// options: The upload options, with the path template and the thumbnail processing.
+/// repair: Regenerates missing thumbnails.
+pub async fn verify(
+    db: &Database,
+    gcp_client: &GCPClient,
+    options: &UploadOptions,
+    repair: bool,
+) -> crate::Result<VerifyReport> {
+    let prefix = options.path_template.prefix();
+
+    // Objects are listed once per bucket.
+    let mut buckets: HashMap<String, HashMap<String, StoredObject>> = HashMap::new();
+    let mut report = VerifyReport::default();
+
+    let mut cursor = Picture::cursor(db).await?;
+    while cursor.advance().await? {
+        let picture: Picture = match cursor.deserialize_current() {
+            Ok(p) => p,
+            Err(e) => {
+                error!("storage::verify, unable to read picture, {:?}", e);
+                continue;
+            }
+        };
+        report.pictures += 1;
+
+        let stored_path = [&picture.path, &picture.thumb_path]
+            .into_iter()
+            .find(|p| !p.is_empty());
+        if stored_path.is_some_and(|p| !p.starts_with(prefix)) {
+            debug!(
+                "storage::verify photo_id: {}, path: {} outside of {}",
+                picture.photo_id, picture.path, prefix
+            );
+            report.outside_prefix += 1;
+            continue;
+        }
+
+        let objects = match buckets.entry(picture.bucket.clone()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let list = gcp_client.list(&picture.bucket, prefix).await?;
+                e.insert(
+                    list.iter()
+                        .map(|o| (o.name.clone(), StoredObject::from(o)))
+                        .collect(),
+                )
+            }
+        };
+
+        for problem in check(&picture, objects) {
+            warn!(
+                "storage::verify photo_id: {}, path: {}, {:?}",
+                picture.photo_id, picture.path, problem
+            );
+
+            match problem {
+                Problem::Missing(Rendition::Full) => report.missing += 1,
+                Problem::Missing(Rendition::Thumb) => {
+                    report.missing += 1;
+                    if !repair || !objects.contains_key(&picture.path) {
+                        continue;
+                    }
+
+                    match repair_thumbnail(db, gcp_client, &picture, options).await {
+                        Ok(o) => {
+                            objects.insert(o.name.clone(), StoredObject::from(&o));
+                            report.repaired += 1;
+                        }
+                        Err(e) => error!(
+                            "storage::verify, unable to repair thumbnail of photo_id: {}, {:?}",
+                            picture.photo_id, e
+                        ),
+                    }
+                }
+                Problem::SizeMismatch { .. } => report.size_mismatches += 1,
+                Problem::HashMismatch => report.hash_mismatches += 1,
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Regenerates the thumbnail of a picture from the stored original, with the processing and
+/// at the path of the upload. The path of the picture is updated when the template moved it.
+async fn repair_thumbnail(
+    db: &Database,
+    gcp_client: &GCPClient,
+    picture: &Picture,
+    options: &UploadOptions,
+) -> crate::Result<Object> {
+    let bytes = gcp_client.download(&picture.bucket, &picture.path).await?;
+    let img = image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)?;
+
+    // Reading the info strip changes the weather data of the copy, it isn't saved.
+    let mut picture = picture.clone();
+    let thumb = picture.make_thumbnail(img, options)?;
+    let thumb_path = options.path_template.render(&picture, Rendition::Thumb);
+
+    let object = gcp_client
+        .save_to_bucket(&picture.bucket, thumb, &thumb_path, gdrive::MIME_JPEG)
+        .await?;
+    if thumb_path != picture.thumb_path {
+        picture.thumb_path = thumb_path;
+        picture.update_paths(db).await?;
+    }
+
+    info!(
+        "storage::verify repaired thumbnail of photo_id: {}",
+        picture.photo_id
+    );
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::cameras::paths::{PathTemplate, Rendition};
+    use crate::cameras::pictures::Picture;
+    use crate::cameras::storage::{check, check_delete, object_picture, Problem, StoredObject};
+    use crate::spypoint::Photo;
+
+    #[test]
+    fn check_objects() {
+        let mut picture = Picture::from(Photo::default());
+        picture.path = String::from("a-full.jpg");
+        picture.thumb_path = String::from("a-thumb.jpg");
+
+        let stored = StoredObject {
+            size: 16483,
+            md5_hash: String::from("1B2M2Y8AsgTpgAmY7PhCfg=="),
+        };
+        let mut objects = HashMap::new();
+        objects.insert(picture.path.clone(), stored.clone());
+
+        // Size and hash weren't recorded.
+        assert_eq!(
+            check(&picture, &objects),
+            vec![Problem::Missing(Rendition::Thumb)]
+        );
+
+        objects.insert(picture.thumb_path.clone(), StoredObject::default());
+        picture.size = 16000;
+        picture.md5_hash = String::from("XrY7u+Ae7tCTyyK7j1rNww==");
+        assert_eq!(
+            check(&picture, &objects),
+            vec![
+                Problem::SizeMismatch {
+                    expected: 16000,
+                    actual: 16483
+                },
+                Problem::HashMismatch
+            ]
+        );
+
+        picture.size = stored.size;
+        picture.md5_hash = stored.md5_hash;
+        assert!(check(&picture, &objects).is_empty());
+
+        // An empty frame that wasn't stored.
+        picture.path = String::new();
+        picture.thumb_path = String::new();
+        assert!(check(&picture, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn picture_of_object() {
+        let t = PathTemplate::default();
+
+        let (id, rendition) = object_picture(
+            &t,
+            "locations/5f145aaf173ca3001571df15/2024/07/66985496c6eb10dbad5c51f6-full.jpg",
+        )
+        .expect("picture object");
+        assert_eq!(id.to_hex(), "66985496c6eb10dbad5c51f6");
+        assert_eq!(rendition, Rendition::Full);
+
+        // Objects of the old layout aren't matched.
+        assert!(object_picture(
+            &t,
+            "locations/FLEX-3TME/7-2024/66985496c6eb10dbad5c51f6-thumb.jpg"
+        )
+        .is_none());
+        assert!(check_delete(&t).is_ok());
+
+        // A template without a fixed prefix lists the whole bucket, nothing is deleted with it
+        // and objects that aren't pictures don't match it.
+        let t = PathTemplate::new("{camera_name}/{yyyy}/{id}-{rendition}.jpg").unwrap();
+        assert!(check_delete(&t).is_err());
+        assert!(object_picture(&t, "backups/2024/mongo-dump.jpg").is_none());
+        assert!(object_picture(&t, "index.html").is_none());
+    }
+}
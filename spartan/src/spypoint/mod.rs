@@ -1,6 +1,6 @@
 use log::debug;
 use reqwest::Method;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::client::Client;
 use crate::Result;
@@ -9,6 +9,7 @@ pub const PATH_LOGIN: &str = "/api/v3/user/login";
 pub const PATH_CAMERAS_ALL: &str = "/api/v3/camera/all";
 pub const PATH_CAMERA: &str = "/api/v3/camera/";
 pub const PATH_PHOTOS: &str = "/api/v3/photo/all";
+pub const PATH_CAMERA_CONFIG: &str = "/api/v3/camera/config/";
 
 // **** Login
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -95,6 +96,10 @@ pub struct Config {
     #[serde(rename = "delay")]
     pub delay: String,
 
+    /// Trigger delay in seconds, reported instead of delay by some models, e.g. the FLEX.
+    #[serde(rename = "motionDelay", skip_serializing_if = "Option::is_none")]
+    pub motion_delay: Option<i64>,
+
     #[serde(rename = "multiShot")]
     pub multi_shot: i64,
 
@@ -198,6 +203,12 @@ pub struct Status {
     #[serde(rename = "batteryType")]
     pub battery_type: String,
 
+    #[serde(rename = "activePowerSource")]
+    pub active_power_source: i64,
+
+    #[serde(rename = "powerSources")]
+    pub power_sources: Vec<PowerSource>,
+
     #[serde(rename = "capability")]
     pub capability: Capability,
 
@@ -216,8 +227,11 @@ pub struct Status {
     #[serde(rename = "modemFirmware")]
     pub modem_firmware: String,
 
-    #[serde(rename = "notifications")]
-    pub notifications: Vec<Option<serde_json::Value>>,
+    #[serde(
+        rename = "notifications",
+        deserialize_with = "deserialize_notifications"
+    )]
+    pub notifications: Vec<Notification>,
 
     #[serde(rename = "serial")]
     pub serial: i64,
@@ -238,14 +252,105 @@ pub struct Status {
     pub coordinates: Vec<Coordinate>,
 }
 
+/// A notification reported by the camera. Notifications this crate doesn't know yet are kept
+/// as Unknown with their raw value.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "serde_json::Value", into = "String")]
+pub enum Notification {
+    SdCardOnePartition,
+    SdCardFull,
+    SdCardMissing,
+    SdCardError,
+    LowBattery,
+    Unknown(String),
+}
+
+impl Notification {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Notification::SdCardOnePartition => "sd_card_one_partition",
+            Notification::SdCardFull => "sd_card_full",
+            Notification::SdCardMissing => "sd_card_missing",
+            Notification::SdCardError => "sd_card_error",
+            Notification::LowBattery => "low_battery",
+            Notification::Unknown(s) => s,
+        }
+    }
+}
+
+impl From<&str> for Notification {
+    fn from(value: &str) -> Self {
+        match value {
+            "sd_card_one_partition" => Notification::SdCardOnePartition,
+            "sd_card_full" => Notification::SdCardFull,
+            "sd_card_missing" => Notification::SdCardMissing,
+            "sd_card_error" => Notification::SdCardError,
+            "low_battery" => Notification::LowBattery,
+            _ => Notification::Unknown(value.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Value> for Notification {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::String(s) => Notification::from(s.as_str()),
+            _ => Notification::Unknown(value.to_string()),
+        }
+    }
+}
+
+/// Reads a list of notifications, null entries are not notifications and are dropped.
+pub fn deserialize_notifications<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<Notification>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let notifications = Vec::<Option<Notification>>::deserialize(deserializer)?;
+    Ok(notifications.into_iter().flatten().collect())
+}
+
+impl From<Notification> for String {
+    fn from(value: Notification) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl std::fmt::Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PowerSource {
+    #[serde(rename = "location")]
+    pub location: String,
+
+    #[serde(rename = "type")]
+    pub type_field: String,
+
+    #[serde(rename = "percentage")]
+    pub percentage: i64,
+
+    /// Voltage in mV.
+    #[serde(rename = "voltage")]
+    pub voltage: i64,
+}
+
 #[derive(Serialize, Debug, Clone, Default, Deserialize)]
 #[serde(default)]
 pub struct Capability {
     #[serde(rename = "hdRequest")]
-    hd_request: bool,
+    pub hd_request: bool,
 
     #[serde(rename = "survivalMode")]
-    survival_mode: bool,
+    pub survival_mode: bool,
+
+    #[serde(rename = "video")]
+    pub video: bool,
 }
 
 #[derive(Serialize, Debug, Clone, Default, Deserialize)]
@@ -265,16 +370,16 @@ pub struct Signal {
     pub bar: i64,
 
     #[serde(rename = "dBm")]
-    d_bm: i64,
+    pub d_bm: i64,
 
     #[serde(rename = "mcc")]
-    mcc: i64,
+    pub mcc: i64,
 
     #[serde(rename = "mnc")]
-    mnc: i64,
+    pub mnc: i64,
 
     #[serde(rename = "type")]
-    signal_type: String,
+    pub signal_type: String,
 
     #[serde(rename = "processed")]
     pub processed: Processed,
@@ -284,18 +389,21 @@ pub struct Signal {
 #[serde(default)]
 pub struct Processed {
     #[serde(rename = "percentage")]
-    percentage: i64,
+    pub percentage: i64,
 
     #[serde(rename = "bar")]
     pub bar: i64,
 
     #[serde(rename = "lowSignal")]
-    low_signal: bool,
+    pub low_signal: bool,
 }
 
 #[derive(Serialize, Debug, Clone, Default, Deserialize)]
 #[serde(default)]
 pub struct Temperature {
+    #[serde(rename = "unit")]
+    pub unit: String,
+
     #[serde(rename = "value")]
     pub value: i64,
 }
@@ -313,65 +421,65 @@ pub struct Subscription {
     pub(crate) payment_status: String,
 
     #[serde(rename = "isActive")]
-    is_active: bool,
+    pub(crate) is_active: bool,
 
     #[serde(rename = "plan")]
-    plan: Plan,
+    pub(crate) plan: Plan,
 
     #[serde(rename = "currency")]
-    currency: String,
+    pub(crate) currency: String,
 
     #[serde(rename = "paymentFrequency")]
-    payment_frequency: String,
+    pub(crate) payment_frequency: String,
 
     #[serde(rename = "isFree")]
-    is_free: bool,
+    pub(crate) is_free: bool,
 
     #[serde(rename = "startDateBillingCycle")]
-    start_date_billing_cycle: String,
+    pub(crate) start_date_billing_cycle: String,
 
     #[serde(rename = "endDateBillingCycle")]
-    end_date_billing_cycle: String,
+    pub(crate) end_date_billing_cycle: String,
 
     #[serde(rename = "monthEndBillingCycle")]
-    month_end_billing_cycle: String,
+    pub(crate) month_end_billing_cycle: String,
 
     #[serde(rename = "photoCount")]
     pub photo_count: i64,
 
     #[serde(rename = "isAutoRenew")]
-    is_auto_renew: bool,
+    pub(crate) is_auto_renew: bool,
 }
 
 #[derive(Serialize, Debug, Clone, Default, Deserialize)]
 #[serde(default)]
 pub struct Plan {
     #[serde(rename = "name")]
-    name: String,
+    pub(crate) name: String,
 
     #[serde(rename = "id")]
-    id: String,
+    pub(crate) id: String,
 
     #[serde(rename = "isActive")]
     is_active: bool,
 
     #[serde(rename = "isFree")]
-    is_free: bool,
+    pub(crate) is_free: bool,
 
     #[serde(rename = "isSelectable")]
     is_selectable: bool,
 
     #[serde(rename = "photoCountPerMonth")]
-    photo_count_per_month: i64,
+    pub(crate) photo_count_per_month: i64,
 
     #[serde(rename = "pricePerMonthIfPaidPerMonth")]
-    price_per_month_if_paid_per_month: i64,
+    pub(crate) price_per_month_if_paid_per_month: i64,
 
     #[serde(rename = "pricePerMonthIfPaidAnnually")]
     price_per_month_if_paid_annually: i64,
 
     #[serde(rename = "pricePerYear")]
-    price_per_year: i64,
+    pub(crate) price_per_year: i64,
 
     #[serde(rename = "pricePerMonthIfPaidAnnuallyInsidersClub")]
     price_per_month_if_paid_annually_insiders_club: i64,
@@ -392,7 +500,7 @@ pub struct Plan {
     show_banner: String,
 
     #[serde(rename = "isUpgradable")]
-    is_upgradable: bool,
+    pub(crate) is_upgradable: bool,
 
     #[serde(rename = "isDowngradable")]
     is_downgradable: bool,
@@ -413,6 +521,33 @@ pub async fn camera(client: &Client, camera_id: String) -> Result<Camera> {
     Ok(result)
 }
 
+/// A change to the configuration of a camera, only the fields set are sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigUpdate {
+    #[serde(rename = "multiShot", skip_serializing_if = "Option::is_none")]
+    pub multi_shot: Option<i64>,
+
+    #[serde(rename = "delay", skip_serializing_if = "Option::is_none")]
+    pub delay: Option<String>,
+
+    /// Trigger delay in seconds, for the models that report motionDelay.
+    #[serde(rename = "motionDelay", skip_serializing_if = "Option::is_none")]
+    pub motion_delay: Option<i64>,
+}
+
+/// update_config changes the configuration of a camera, the camera applies it on its next
+/// transmission.
+pub async fn update_config(client: &Client, camera_id: &str, update: &ConfigUpdate) -> Result<()> {
+    let path = format!("{}{}", PATH_CAMERA_CONFIG, camera_id);
+
+    debug!("spypoint::update_config, request: {:?}", update);
+    let _: serde_json::Value = client
+        .send_request(update, Method::PUT, path.as_str(), true)
+        .await?;
+
+    Ok(())
+}
+
 // ****** photos
 
 #[derive(Serialize, Debug, Clone, Default, Deserialize)]
@@ -529,7 +664,8 @@ mod tests {
     use crate::{client, spypoint};
     use crate::client::Server;
     use crate::spypoint::{
-        Login, LoginResponse, PATH_CAMERA, PATH_CAMERAS_ALL, PATH_LOGIN, PATH_PHOTOS,
+        ConfigUpdate, Login, LoginResponse, Notification, Status, PATH_CAMERA, PATH_CAMERAS_ALL,
+        PATH_CAMERA_CONFIG, PATH_LOGIN, PATH_PHOTOS,
     };
 
     #[test]
@@ -665,6 +801,63 @@ mod tests {
         });
     }
 
+    #[test]
+    fn update_config() {
+        let mock_server = MockServer::start();
+        let url = format!("http://{}", mock_server.address());
+
+        let camera_id = "66985496c6eb10dbad5c51f6";
+        let path = format!("{}{}", PATH_CAMERA_CONFIG, camera_id);
+
+        let config_mock = mock_server.mock(|when, then| {
+            when.method(PUT)
+                .path(path)
+                .header("Authorization", "Bearer token")
+                .json_body(serde_json::json!({ "delay": "5min" }));
+            then.status(200).body("{}");
+        });
+
+        let server = Server {
+            user_name: String::from("ed"),
+            password: String::from("money"),
+            host: url,
+        };
+
+        let client = client::Client::new(server).expect("spypoint client");
+        client.set_auth(String::from("token"));
+
+        let update = ConfigUpdate {
+            delay: Some(String::from("5min")),
+            ..Default::default()
+        };
+
+        tokio_test::block_on(async {
+            let result = spypoint::update_config(&client, camera_id, &update).await;
+
+            config_mock.assert();
+            assert!(result.is_ok());
+        });
+    }
+
+    #[test]
+    fn notifications() {
+        let n: Vec<Notification> =
+            serde_json::from_str(r#"["sd_card_one_partition", "new_firmware", {"code": 7}]"#)
+                .unwrap();
+
+        assert_eq!(n[0], Notification::SdCardOnePartition);
+        assert_eq!(n[1], Notification::Unknown(String::from("new_firmware")));
+        assert_eq!(n[2], Notification::Unknown(String::from(r#"{"code":7}"#)));
+        assert_eq!(
+            serde_json::to_string(&n[..2]).unwrap(),
+            r#"["sd_card_one_partition","new_firmware"]"#
+        );
+
+        let status = serde_json::json!({ "notifications": ["low_battery", null] });
+        let status: Status = serde_json::from_value(status).unwrap();
+        assert_eq!(status.notifications, vec![Notification::LowBattery]);
+    }
+
     #[test]
     fn all_cameras() {
         let mock_server = MockServer::start();
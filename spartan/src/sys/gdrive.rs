@@ -1,4 +1,6 @@
-use cloud_storage::{Client, Object};
+use bytes::Bytes;
+use cloud_storage::{Client, ListRequest, Object};
+use futures_util::{StreamExt, TryStream};
 
 pub const MIME_JPEG: &str = "image/jpeg";
 
@@ -32,4 +34,72 @@ impl GCPClient {
     ) -> cloud_storage::Result<Object> {
         self.inner.object().create(bucket, img, path, mime).await
     }
+
+    /// Uploads the content of a stream without buffering it.
+    ///
+    /// Arguments:
+    ///
+    /// bucket: The name of the bucket.
+    /// stream: The content of the object.
+    /// length: The length of the content, when known.
+    /// path: The path of the object.
+    /// mime: The content type of the object.
+    pub async fn save_stream<S>(
+        &self,
+        bucket: &str,
+        stream: S,
+        length: Option<u64>,
+        path: &str,
+        mime: &str,
+    ) -> cloud_storage::Result<Object>
+    where
+        S: TryStream + Send + Sync + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        Bytes: From<S::Ok>,
+    {
+        self.inner
+            .object()
+            .create_streamed(bucket, stream, length, path, mime)
+            .await
+    }
+
+    /// Returns the objects of a bucket whose path starts with the prefix.
+    pub async fn list(&self, bucket: &str, prefix: &str) -> cloud_storage::Result<Vec<Object>> {
+        let request = ListRequest {
+            prefix: Some(prefix.to_string()),
+            ..Default::default()
+        };
+
+        let mut pages = Box::pin(self.inner.object().list(bucket, request).await?);
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            objects.extend(page?.items);
+        }
+
+        Ok(objects)
+    }
+
+    /// Downloads the content of an object.
+    pub async fn download(&self, bucket: &str, path: &str) -> cloud_storage::Result<Vec<u8>> {
+        self.inner.object().download(bucket, path).await
+    }
+
+    /// Deletes an object from a bucket.
+    pub async fn delete(&self, bucket: &str, path: &str) -> cloud_storage::Result<()> {
+        self.inner.object().delete(bucket, path).await
+    }
+
+    /// Moves an object to a new path in the same bucket.
+    pub async fn move_object(
+        &self,
+        bucket: &str,
+        from: &str,
+        to: &str,
+    ) -> cloud_storage::Result<Object> {
+        let object = self.inner.object().read(bucket, from).await?;
+        let moved = self.inner.object().copy(&object, bucket, to).await?;
+        self.inner.object().delete(bucket, from).await?;
+
+        Ok(moved)
+    }
 }
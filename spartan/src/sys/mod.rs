@@ -2,3 +2,4 @@ pub mod gdrive;
 pub mod mgo;
 pub mod slack;
 pub mod sync;
+pub mod weather;
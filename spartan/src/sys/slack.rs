@@ -63,3 +63,30 @@ pub async fn send_message(client: reqwest::Client, url: String, msg: String) ->
 
     Ok(())
 }
+
+/// Sends a camera alert, resolved alerts are shown in green.
+pub async fn send_alert(
+    client: reqwest::Client,
+    url: String,
+    title: String,
+    msg: String,
+    resolved: bool,
+) -> crate::Result<()> {
+    if url.is_empty() {
+        return Ok(());
+    }
+
+    let color = if resolved { "#2eb886" } else { "#f00" };
+    let m = Message {
+        text: msg,
+        attachments: vec![Attachment {
+            title,
+            color: String::from(color),
+            ..Default::default()
+        }],
+    };
+
+    client.post(url).json(&m).send().await?;
+
+    Ok(())
+}
@@ -3,7 +3,12 @@ use mongodb::{Collection, Database};
 use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
 
+use crate::cameras::pictures::Picture;
+use crate::cameras::Camera;
+use crate::spypoint::ConfigUpdate;
+
 const SYNC_COLLECTION: &str = "sync";
+const SYNC_ERROR_COLLECTION: &str = "sync_errors";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SyncError {
@@ -15,8 +20,53 @@ pub struct SyncError {
     photo_id: String,
     photo_url: String,
     photo_file_name: String,
+    /// Rendition that was downloaded.
+    #[serde(default)]
+    photo_quality: String,
     photo_timestamp: String,
     photo_date_utc: DateTime,
+    #[serde(default)]
+    message: String,
+}
+
+impl SyncError {
+    /// An error syncing a picture of a camera.
+    ///
+    /// Arguments:
+    ///
+    /// stage: The step of the sync that failed, e.g. "validate".
+    /// camera: The camera that took the picture.
+    /// picture: The picture that failed.
+    /// message: The error.
+    pub fn new(stage: &str, camera: &Camera, picture: &Picture, message: String) -> Self {
+        SyncError {
+            date: DateTime::now(),
+            stage: stage.to_string(),
+            camera_type: camera.r#type.clone(),
+            camera_id: camera.camera_id.clone(),
+            camera_account_id: camera.account_id.clone(),
+            photo_id: picture.photo_id.clone(),
+            photo_url: picture.photo_url.clone(),
+            photo_file_name: picture
+                .photo_url
+                .split('?')
+                .next()
+                .and_then(|url| url.rsplit('/').next())
+                .unwrap_or_default()
+                .to_string(),
+            photo_quality: picture.quality.as_str().to_string(),
+            photo_timestamp: picture.photo_time_stamp.clone(),
+            photo_date_utc: picture.date,
+            message,
+        }
+    }
+
+    pub async fn save(&self, db: &Database) -> crate::Result<()> {
+        let coll: Collection<SyncError> = db.collection(SYNC_ERROR_COLLECTION);
+        coll.insert_one(self).await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -29,6 +79,27 @@ pub struct SyncResult {
     pub uploaded: i64,
     pub skipped: i64,
     pub errors: i64,
+    /// Pictures stored in a smaller quality that were replaced with a better rendition.
+    #[serde(default)]
+    pub upgraded: i64,
+    /// Uploaded pictures that are likely empty frames.
+    #[serde(default)]
+    pub empty: i64,
+    /// Photos transmitted this month, 0 when the plan is unlimited.
+    #[serde(default)]
+    pub photo_count: i64,
+    /// Photos included per month, 0 when the plan is unlimited.
+    #[serde(default)]
+    pub photo_quota: i64,
+    /// Photos expected by the end of the month at the current rate.
+    #[serde(default)]
+    pub projected_photos: i64,
+    /// Config change recommended to stay within the photo quota.
+    #[serde(default)]
+    pub recommended_config: Option<ConfigUpdate>,
+    /// Whether the recommended config change was sent to the camera.
+    #[serde(default)]
+    pub config_updated: bool,
 }
 
 impl SyncResult {
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::sync::Mutex;
+
+use chrono::{DurationRound, TimeDelta};
+use log::debug;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::cameras::GPS;
+
+const HOURLY_FIELDS: &str =
+    "temperature_2m,surface_pressure,wind_speed_10m,wind_direction_10m,weather_code";
+
+/// Weather conditions at a location for a given hour.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Observation {
+    /// Pressure in hPa.
+    pub barometric_pressure: f64,
+    /// Temperature in Fahrenheit.
+    pub temperature: f64,
+    /// Wind speed in mph.
+    pub wind_speed: f64,
+    pub wind_degrees: i64,
+    pub conditions: String,
+    pub observation_time: String,
+}
+
+/// A source of weather observations for pictures.
+pub trait WeatherProvider {
+    /// Returns the weather at the camera's location for the hour the picture was taken.
+    ///
+    /// Arguments:
+    ///
+    /// camera_id: The id of the camera that took the picture.
+    /// gps: The location of the camera.
+    /// date: The date the picture was taken.
+    fn observation(
+        &self,
+        camera_id: &str,
+        gps: &GPS,
+        date: DateTime,
+    ) -> impl Future<Output = crate::Result<Observation>>;
+}
+
+/// Weather provider backed by an HTTP API that follows the Open-Meteo hourly format.
+pub struct HttpWeatherProvider {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+}
+
+impl HttpWeatherProvider {
+    pub fn new(client: reqwest::Client, url: String, api_key: String) -> Self {
+        Self {
+            client,
+            url,
+            api_key,
+        }
+    }
+
+    /// Loads the provider from WEATHER_URL and WEATHER_API_KEY. Returns None when WEATHER_URL
+    /// isn't set, so weather enrichment stays off.
+    pub fn from_env(client: reqwest::Client) -> Option<Self> {
+        let url = env::var("WEATHER_URL").ok()?;
+        if url.is_empty() {
+            return None;
+        }
+
+        let api_key = env::var("WEATHER_API_KEY").unwrap_or_default();
+        Some(Self::new(client, url, api_key))
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct HourlyResponse {
+    hourly: Hourly,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct Hourly {
+    time: Vec<String>,
+    temperature_2m: Vec<Option<f64>>,
+    surface_pressure: Vec<Option<f64>>,
+    wind_speed_10m: Vec<Option<f64>>,
+    wind_direction_10m: Vec<Option<f64>>,
+    weather_code: Vec<Option<i64>>,
+}
+
+impl WeatherProvider for HttpWeatherProvider {
+    async fn observation(
+        &self,
+        _camera_id: &str,
+        gps: &GPS,
+        date: DateTime,
+    ) -> crate::Result<Observation> {
+        let (lat, lng) = (gps.latitude(), gps.longitude());
+
+        let hour = date.to_chrono().duration_trunc(TimeDelta::hours(1))?;
+        let day = hour.format("%Y-%m-%d").to_string();
+
+        let mut query = vec![
+            ("latitude", lat.to_string()),
+            ("longitude", lng.to_string()),
+            ("start_date", day.clone()),
+            ("end_date", day),
+            ("hourly", HOURLY_FIELDS.to_string()),
+            ("temperature_unit", "fahrenheit".to_string()),
+            ("wind_speed_unit", "mph".to_string()),
+            ("timezone", "UTC".to_string()),
+        ];
+        if !self.api_key.is_empty() {
+            query.push(("apikey", self.api_key.clone()));
+        }
+
+        let resp = self.client.get(&self.url).query(&query).send().await?;
+        if !resp.status().is_success() {
+            return Err(Box::from(format!(
+                "weather::observation http_status {}",
+                resp.status().as_u16()
+            )));
+        }
+
+        let body: HourlyResponse = resp.json().await?;
+        let time = hour.format("%Y-%m-%dT%H:00").to_string();
+        let h = body.hourly;
+
+        let Some(i) = h.time.iter().position(|t| *t == time) else {
+            return Err(Box::from(format!(
+                "weather::observation no observation for {}",
+                time
+            )));
+        };
+
+        let value = |v: &Vec<Option<f64>>| v.get(i).copied().flatten().unwrap_or_default();
+        let code = h.weather_code.get(i).copied().flatten().unwrap_or(-1);
+
+        Ok(Observation {
+            barometric_pressure: value(&h.surface_pressure),
+            temperature: value(&h.temperature_2m),
+            wind_speed: value(&h.wind_speed_10m),
+            wind_degrees: value(&h.wind_direction_10m).round() as i64,
+            conditions: weather_label(code).to_string(),
+            observation_time: hour.to_rfc3339(),
+        })
+    }
+}
+
+/// Caches the observations of a provider per camera and hour, so pictures taken in the same
+/// hour don't query the provider again.
+pub struct CachedWeatherProvider<P: WeatherProvider> {
+    inner: P,
+    cache: Mutex<HashMap<(String, i64), Observation>>,
+}
+
+impl<P: WeatherProvider> CachedWeatherProvider<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<P: WeatherProvider> WeatherProvider for CachedWeatherProvider<P> {
+    async fn observation(
+        &self,
+        camera_id: &str,
+        gps: &GPS,
+        date: DateTime,
+    ) -> crate::Result<Observation> {
+        let key = (camera_id.to_string(), date.timestamp_millis() / 3_600_000);
+
+        if let Some(o) = self.cache.lock().unwrap().get(&key) {
+            debug!("weather::observation cache hit {:?}", key);
+            return Ok(o.clone());
+        }
+
+        let o = self.inner.observation(camera_id, gps, date).await?;
+        self.cache.lock().unwrap().insert(key, o.clone());
+
+        Ok(o)
+    }
+}
+
+/// Returns the label of a WMO weather code.
+pub fn weather_label(code: i64) -> &'static str {
+    match code {
+        0 => "Clear",
+        1 => "Mostly Clear",
+        2 => "Partly Cloudy",
+        3 => "Overcast",
+        45 | 48 => "Fog",
+        51..=57 => "Drizzle",
+        61..=67 => "Rain",
+        71..=77 => "Snow",
+        80..=82 => "Rain Showers",
+        85 | 86 => "Snow Showers",
+        95..=99 => "Thunderstorm",
+        _ => "",
+    }
+}
+
+/// Returns the short and long cardinal labels (e.g. "NNE", "North Northeast") of a wind direction.
+pub fn cardinal_labels(degrees: i64) -> (&'static str, &'static str) {
+    const LABELS: [(&str, &str); 16] = [
+        ("N", "North"),
+        ("NNE", "North Northeast"),
+        ("NE", "Northeast"),
+        ("ENE", "East Northeast"),
+        ("E", "East"),
+        ("ESE", "East Southeast"),
+        ("SE", "Southeast"),
+        ("SSE", "South Southeast"),
+        ("S", "South"),
+        ("SSW", "South Southwest"),
+        ("SW", "Southwest"),
+        ("WSW", "West Southwest"),
+        ("W", "West"),
+        ("WNW", "West Northwest"),
+        ("NW", "Northwest"),
+        ("NNW", "North Northwest"),
+    ];
+
+    let index = ((degrees.rem_euclid(360) as f64 / 22.5).round() as usize) % 16;
+    LABELS[index]
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use mongodb::bson::DateTime;
+
+    use crate::cameras::GPS;
+    use crate::sys::weather::{
+        cardinal_labels, CachedWeatherProvider, HttpWeatherProvider, WeatherProvider,
+    };
+
+    #[test]
+    fn observation_is_cached_per_hour() {
+        let mock_server = MockServer::start();
+        let url = format!("http://{}/v1/forecast", mock_server.address());
+
+        let weather_mock = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v1/forecast")
+                .query_param("start_date", "2024-07-17")
+                .query_param("latitude", "25.544241");
+            then.status(200).body(HOURLY_RESPONSE);
+        });
+
+        let provider = CachedWeatherProvider::new(HttpWeatherProvider::new(
+            reqwest::Client::new(),
+            url,
+            String::new(),
+        ));
+
+        let gps = GPS::new(25.544241, -80.439992);
+
+        tokio_test::block_on(async {
+            let date = DateTime::parse_rfc3339_str("2024-07-17T19:51:41.000Z").unwrap();
+            let o = provider
+                .observation("66985496c6eb10dbad5c51f6", &gps, date)
+                .await
+                .expect("observation");
+
+            assert_eq!(o.temperature, 86.4);
+            assert_eq!(o.barometric_pressure, 1012.3);
+            assert_eq!(o.wind_degrees, 95);
+            assert_eq!(o.conditions, "Partly Cloudy");
+
+            let date = DateTime::parse_rfc3339_str("2024-07-17T19:10:00.000Z").unwrap();
+            provider
+                .observation("66985496c6eb10dbad5c51f6", &gps, date)
+                .await
+                .expect("cached observation");
+
+            weather_mock.assert_hits(1);
+        });
+    }
+
+    #[test]
+    fn cardinal() {
+        assert_eq!(cardinal_labels(0), ("N", "North"));
+        assert_eq!(cardinal_labels(95), ("E", "East"));
+        assert_eq!(cardinal_labels(350), ("N", "North"));
+        assert_eq!(cardinal_labels(-90), ("W", "West"));
+    }
+
+    const HOURLY_RESPONSE: &str = r#"{
+  "latitude": 25.54,
+  "longitude": -80.44,
+  "hourly": {
+    "time": ["2024-07-17T18:00", "2024-07-17T19:00", "2024-07-17T20:00"],
+    "temperature_2m": [88.1, 86.4, 84.0],
+    "surface_pressure": [1012.0, 1012.3, 1012.9],
+    "wind_speed_10m": [7.2, 6.8, 5.1],
+    "wind_direction_10m": [90.0, 95.0, 101.0],
+    "weather_code": [1, 2, 3]
+  }
+}"#;
+}
@@ -0,0 +1,118 @@
+use log::info;
+use mongodb::Database;
+
+use spartan::cameras::paths::PathTemplate;
+use spartan::cameras::pictures::UploadOptions;
+use spartan::cameras::{pictures, storage, Camera};
+use spartan::sys::gdrive::GCPClient;
+
+pub const BACKFILL_ASTRONOMY: &str = "backfill-astronomy";
+pub const LIST_CAMERAS: &str = "cameras";
+pub const MIGRATE_PATHS: &str = "migrate-paths";
+pub const RECONCILE: &str = "reconcile";
+pub const VERIFY: &str = "verify";
+
+/// Flag of the reconcile command that deletes the orphaned objects.
+pub const FLAG_DELETE: &str = "--delete";
+/// Flag of the verify command that regenerates missing thumbnails.
+pub const FLAG_REPAIR: &str = "--repair";
+
+/// What the commands work with.
+pub struct Context<'a> {
+    pub db: &'a Database,
+    pub gcp_client: &'a GCPClient,
+    pub bucket: &'a str,
+    /// The layout of the picture objects in the bucket.
+    pub template: &'a PathTemplate,
+    /// Processing of the pictures, thumbnails are repaired the way they are made on upload.
+    pub upload_options: &'a UploadOptions,
+}
+
+/// Runs a maintenance command instead of the sync.
+///
+/// Arguments:
+///
+/// command: The name of the command, passed as the first argument to the program.
+/// args: The arguments that follow the command.
+/// ctx: The database and storage the command works with.
+pub async fn run(command: &str, args: &[String], ctx: &Context<'_>) -> spartan::Result<()> {
+    let db = ctx.db;
+
+    match command {
+        BACKFILL_ASTRONOMY => {
+            let updated = pictures::backfill_astronomy(db).await?;
+            info!(
+                "commands::run {} complete, {} picture(s) updated...",
+                command, updated
+            );
+        }
+        LIST_CAMERAS => {
+            let cameras = Camera::all(db).await?;
+            list_cameras(&cameras);
+        }
+        MIGRATE_PATHS => {
+            let report = pictures::migrate_paths(db, ctx.gcp_client, ctx.template).await?;
+            info!(
+                "commands::run {} complete, {} picture(s) moved, {} failed...",
+                command, report.moved, report.failed
+            );
+        }
+        RECONCILE => {
+            let delete = args.iter().any(|a| a == FLAG_DELETE);
+            let report =
+                storage::reconcile(db, ctx.gcp_client, ctx.bucket, ctx.template, delete).await?;
+            info!(
+                "commands::run {} complete, objects: {}, orphans: {}, registered: {}, unknown: {}, deleted: {}...",
+                command,
+                report.objects,
+                report.orphans,
+                report.registered,
+                report.unknown,
+                report.deleted
+            );
+        }
+        VERIFY => {
+            let repair = args.iter().any(|a| a == FLAG_REPAIR);
+            let report = storage::verify(db, ctx.gcp_client, ctx.upload_options, repair).await?;
+            info!(
+                "commands::run {} complete, pictures: {}, missing: {}, size mismatches: {}, hash mismatches: {}, repaired: {}, outside prefix: {}...",
+                command,
+                report.pictures,
+                report.missing,
+                report.size_mismatches,
+                report.hash_mismatches,
+                report.repaired,
+                report.outside_prefix
+            );
+        }
+        _ => return Err(Box::from(format!("unknown command {}", command))),
+    }
+
+    Ok(())
+}
+
+/// Prints the cameras with their battery, estimated empty date, signal and last transmission.
+fn list_cameras(cameras: &[Camera]) {
+    println!(
+        "{:<20} {:>7} {:>8} {:<10} {:>6} {:<20}",
+        "NAME", "BATTERY", "VOLTAGE", "EMPTY", "SIGNAL", "LAST TRANSMISSION"
+    );
+
+    for c in cameras {
+        let empty = c
+            .battery_empty_date
+            .map(|d| d.to_chrono().format("%Y-%m-%d").to_string())
+            .unwrap_or(String::from("-"));
+        let last = c
+            .status
+            .last_transmission
+            .to_chrono()
+            .format("%Y-%m-%d %H:%M")
+            .to_string();
+
+        println!(
+            "{:<20} {:>6}% {:>7.2}V {:<10} {:>6} {:<20}",
+            c.name, c.status.battery_level, c.status.voltage, empty, c.status.signal, last
+        );
+    }
+}
@@ -1,27 +1,57 @@
 use std::{env, process};
+use std::sync::Arc;
 use std::time::Duration;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use mongodb::bson::{DateTime, doc};
 
 use spartan::{client, spypoint, sys::mgo};
 use spartan::cameras::Camera;
-use spartan::cameras::pictures::Picture;
+use spartan::cameras::alerts;
+use spartan::cameras::alerts::Thresholds;
+#[cfg(feature = "detect")]
+use spartan::cameras::detect::OnnxDetector;
+use spartan::cameras::detect::Detector;
+use spartan::cameras::enhance::EnhanceOptions;
+use spartan::cameras::geocode::ZipCentroids;
+use spartan::cameras::history::{StatusHistory, StatusSnapshot};
+use spartan::cameras::paths::PathTemplate;
+use spartan::cameras::pictures::{EmptyFrames, InvalidImage, Picture, Quality, UploadOptions};
+use spartan::cameras::quota;
+use spartan::cameras::quota::{QuotaAdjustment, QuotaUsage};
 use spartan::client::Server;
 use spartan::spypoint::Login;
 use spartan::sys::gdrive::GCPClient;
 use spartan::sys::slack;
-use spartan::sys::sync::SyncResult;
+use spartan::sys::sync::{SyncError, SyncResult};
+use spartan::sys::weather::{CachedWeatherProvider, HttpWeatherProvider};
+
+mod commands;
 
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
 const DAYS_OF_PICS: u64 = 2;
+/// Days of status history used to estimate when the batteries run out.
+const BATTERY_HISTORY_DAYS: i64 = 90;
 
 /// Main entry into program. The following variables are expected to be set in the
 /// environment or the app will panic.
 ///
+/// When a command is passed as the first argument it is run instead of the sync.
+///
+/// ## Commands
+/// backfill-astronomy computes the sun and moon phase of every picture in the database.
+/// cameras lists the cameras with their battery, estimated empty date and signal.
+/// migrate-paths moves the picture objects to the STORAGE_PATH_TEMPLATE layout.
+/// reconcile lists the objects in the bucket without a picture, registers those that are the
+/// missing object of their picture and, with --delete, deletes the others that match
+/// STORAGE_PATH_TEMPLATE. --delete is refused when the template starts with a placeholder.
+/// verify checks the objects of every picture under the STORAGE_PATH_TEMPLATE prefix exist with
+/// the recorded size and hash, with --repair missing thumbnails are regenerated from the
+/// original with the thumbnail settings.
+///
 /// ## Mongo Env vars, see mgo module for more info.
 /// MONGO_CLUSTER=<bool>
 /// MONGO_HOSTS=<string>
@@ -43,6 +73,64 @@ const DAYS_OF_PICS: u64 = 2;
 ///
 /// ##MISC
 /// SLACK_URL=<string>
+/// SYNC_DAYS=<int>
+/// ZIP_CENTROIDS_FILE=<path> zip code centroids used to set the zip of cameras, a CSV of
+/// zip,latitude,longitude or the census ZCTA gazetteer file, read once at startup. Cameras get
+/// no zip when not set.
+///
+/// ##ALERTS
+/// ALERT_BATTERY=<int> battery percentage at or below which an alert is sent, default 20.
+/// ALERT_BATTERY_DAYS=<int> days before the estimated empty date an alert is sent, default 14.
+/// ALERT_MEMORY=<float> percentage of the SD card used at which an alert is sent, default 90.
+/// ALERT_SIGNAL=<int> signal bars at or below which an alert is sent, default 1.
+/// ALERT_STALE_HOURS=<int> hours without a transmission before an alert is sent, default 36.
+/// ALERT_QUOTA=<float> percentage of the monthly photo quota used at which an alert is sent,
+/// default 90.
+/// ALERT_BILLING_DAYS=<int> days before a billing cycle without auto-renew ends an alert is
+/// sent, default 7.
+///
+/// ##QUOTA
+/// QUOTA_ADJUST=<bool> sends the recommended config change (fewer shots per trigger or a longer
+/// delay) to cameras projected to run out of monthly photos. A change is escalated once the
+/// camera applied it and the photos taken since still run out.
+///
+/// ##STORAGE
+/// STORAGE_PATH_TEMPLATE=<string> layout of the picture objects in the bucket, default
+/// "locations/{camera_id}/{yyyy}/{mm}/{id}-{rendition}.jpg". Placeholders are {camera_id},
+/// {camera_name}, {yyyy}, {mm}, {dd}, {id}, {photo_id}, {tag} and {rendition}.
+/// PHOTO_QUALITY=<small|medium|large> rendition of the photos downloaded from Spypoint, default
+/// large. A smaller rendition is stored when it fails, and upgraded on a later run. A failed
+/// upgrade is retried after a backoff of 6 hours, doubled after each failure up to a week.
+///
+/// ##PICTURE PROCESSING
+/// BANNER_READ=<bool> reads temperature, moon phase and time from the info strip.
+/// BANNER_STRIP=<bool> removes the info strip from thumbnails.
+/// BANNER_RATIO=<float> height of the info strip as a ratio of the picture height.
+/// THUMB_ORIENT=<bool> rotates thumbnails upright using the EXIF orientation.
+/// THUMB_AUTO_LEVELS=<bool> stretches the contrast of night IR thumbnails.
+/// THUMB_SHARPEN=<bool> sharpens thumbnails.
+/// EMPTY_FRAMES=<keep|thumbnail|skip> what is stored of frames that barely differ from recent
+/// frames of the camera, the picture and thumbnail, only the thumbnail or nothing. Default keep.
+/// Skip only drops frames the detection model found empty, the others get a thumbnail.
+/// EMPTY_MAX_CHANGED=<float> share of changed cells below which a frame is likely empty,
+/// default 0.015. Lower it for cameras where small or distant animals are missed.
+///
+/// ##WEATHER
+/// WEATHER_URL=<string> hourly weather API (e.g. https://api.open-meteo.com/v1/forecast),
+/// weather enrichment is off when not set.
+/// WEATHER_API_KEY=<string>
+///
+/// ##DETECTION
+/// Needs the detect feature, `cargo build -p sync-rs -r --features detect`. The Docker image is
+/// built for musl without it, ONNX Runtime has no prebuilt musl library, so detection needs a
+/// glibc build of the binary.
+/// DETECT_MODEL=<path> YOLO model in ONNX format run on the CPU, e.g. MegaDetector v5,
+/// detection is off when not set.
+/// DETECT_LABELS=<string> comma separated labels of the model classes, default
+/// "animal,human,vehicle". Labels other than deer, buck, turkey, hog, animal, human and vehicle
+/// are refused.
+/// DETECT_INPUT_SIZE=<int> width and height of the model input, default 640.
+/// DETECT_CONFIDENCE=<float> minimum confidence of a detection, default 0.2.
 ///
 #[tokio::main]
 async fn main() {
@@ -70,6 +158,10 @@ async fn main() {
     // It loads the GCP JSON Key from the env. See GCPClient for more details.
     let gcp_client = GCPClient::default();
 
+    // Weather provider, observations are cached per camera and hour.
+    let weather =
+        HttpWeatherProvider::from_env(client.http_client()).map(CachedWeatherProvider::new);
+
     // Load and Initialize DB Client
     // Tuple returned (Client, DB)
     let mgo = mgo::load_mongo_client()
@@ -94,6 +186,49 @@ async fn main() {
 
     info!("mongo connected to database, {:?}...", db.name());
 
+    if let Err(e) = Camera::create_indexes(&db).await {
+        error!("sync::main error creating camera indexes, {:?}", e);
+    }
+
+    if let Err(e) = Picture::create_indexes(&db).await {
+        error!("sync::main error creating picture indexes, {:?}", e);
+    }
+
+    if let Err(e) = StatusSnapshot::create_collection(&db).await {
+        error!(
+            "sync::main error creating camera status collection, {:?}",
+            e
+        );
+    }
+
+    // Run a maintenance command instead of the sync.
+    if let Some(command) = env::args().nth(1) {
+        let args: Vec<String> = env::args().skip(2).collect();
+        let ctx = commands::Context {
+            db: &db,
+            gcp_client: &gcp_client,
+            bucket: &config.gcp_bucket,
+            template: &config.upload_options.path_template,
+            upload_options: &config.upload_options,
+        };
+
+        if let Err(e) = commands::run(&command, &args, &ctx).await {
+            let msg = format!("sync::main error running command {}, {:?}", command, e);
+            error!("{}", msg);
+            // send msg to Slack
+            let _ = slack::save_error(
+                client.http_client(),
+                config.slack_url.clone(),
+                msg,
+                String::from("Sync.rs"),
+            )
+            .await;
+            process::exit(1);
+        }
+
+        return;
+    }
+
     // Login
     let l = Login {
         username: client.user(),
@@ -157,6 +292,13 @@ async fn main() {
             uploaded: 0,
             skipped: 0,
             errors: 0,
+            upgraded: 0,
+            empty: 0,
+            photo_count: 0,
+            photo_quota: 0,
+            projected_photos: 0,
+            recommended_config: None,
+            config_updated: false,
         };
 
         // Loads camera details
@@ -184,15 +326,141 @@ async fn main() {
         };
 
         //  Convert and Upsert Camera
-        let spartan_camera = Camera::from(camera_detail);
+        let mut spartan_camera = Camera::from(camera_detail);
+        if let Some(zips) = &config.zip_centroids {
+            spartan_camera.apply_zip(zips);
+        }
+
+        // Record the camera status history
+        if let Err(e) = StatusSnapshot::from(&spartan_camera).save(&db).await {
+            let msg = format!(
+                "sync::main saving camera status, {}...{:?}",
+                camera.clone().config.name,
+                e
+            );
+            error!("{}", msg);
+            // send msg to Slack
+            let _ = slack::save_error(
+                client.http_client(),
+                config.slack_url.clone(),
+                msg,
+                String::from("Sync.rs"),
+            )
+            .await;
+
+            err_counter += 1;
+        }
+
+        // Estimate when the batteries run out from the status history.
+        match StatusHistory::load(&db, &spartan_camera.camera_id, BATTERY_HISTORY_DAYS).await {
+            Ok(h) => spartan_camera.battery_empty_date = h.estimated_empty_date(),
+            Err(e) => warn!(
+                "sync::main error loading status history, {}...{:?}",
+                camera.clone().config.name,
+                e
+            ),
+        }
+
+        // Continue the location history of the stored camera. When it can't be loaded the
+        // camera isn't saved, the save would replace the stored history.
+        let loaded = match Camera::find(&db, &spartan_camera.camera_id).await {
+            Ok(previous) => {
+                spartan_camera.track_location(previous.as_ref(), DateTime::now());
+                if let Some(p) = previous {
+                    spartan_camera.apply_user_fields(&p);
+                    spartan_camera.quota_adjustment = p.quota_adjustment;
+                }
+                true
+            }
+            Err(e) => {
+                let msg = format!(
+                    "sync::main loading stored camera, {}...{:?}",
+                    camera.clone().config.name,
+                    e
+                );
+                error!("{}", msg);
+                // send msg to Slack
+                let _ = slack::save_error(
+                    client.http_client(),
+                    config.slack_url.clone(),
+                    msg,
+                    String::from("Sync.rs"),
+                )
+                .await;
+
+                err_counter += 1;
+                false
+            }
+        };
+
+        match Picture::count(&db, &spartan_camera.camera_id).await {
+            Ok(x) => spartan_camera.usage.stored_photos = x,
+            Err(e) => warn!(
+                "sync::main error counting stored pictures, {}...{:?}",
+                camera.clone().config.name,
+                e
+            ),
+        }
 
         debug!("sync.rs::main camera to save\n{:?}\n", spartan_camera);
 
-        match spartan_camera.save(&db).await {
-            Ok(()) => {}
+        if loaded {
+            match spartan_camera.save(&db).await {
+                Ok(()) => {}
+                Err(e) => {
+                    let msg = format!(
+                        "sync::main saving camera, {}...{:?}",
+                        camera.clone().config.name,
+                        e
+                    );
+                    error!("{}", msg);
+                    // send msg to Slack
+                    let _ = slack::save_error(
+                        client.http_client(),
+                        config.slack_url.clone(),
+                        msg,
+                        String::from("Sync.rs"),
+                    )
+                    .await;
+
+                    err_counter += 1;
+                    continue;
+                }
+            }
+        }
+
+        // Health alerts, each condition is notified once when raised and once when resolved.
+        match alerts::update(&db, &spartan_camera, &config.alert_thresholds).await {
+            Ok(changes) => {
+                for (a, resolved) in changes
+                    .raised
+                    .iter()
+                    .map(|a| (a, false))
+                    .chain(changes.resolved.iter().map(|a| (a, true)))
+                {
+                    let title = if resolved {
+                        format!("{} resolved: {}", a.camera_name, a.kind)
+                    } else {
+                        format!("{} alert: {}", a.camera_name, a.kind)
+                    };
+                    info!("sync::main {}", title);
+
+                    if let Err(e) = slack::send_alert(
+                        client.http_client(),
+                        config.slack_url.clone(),
+                        title,
+                        a.message.clone(),
+                        resolved,
+                    )
+                    .await
+                    {
+                        error!("sync::main error sending alert, {:?}", e);
+                    }
+                }
+            }
             Err(e) => {
                 let msg = format!(
-                    "sync::main saving camera, {}...{:?}",
+                    "sync::main updating camera alerts, {}...{:?}",
                     camera.clone().config.name,
                     e
                 );
@@ -207,7 +475,71 @@ async fn main() {
                 .await;
 
                 err_counter += 1;
-                continue;
+            }
+        }
+
+        // Monthly photo quota, a camera over its quota stops transmitting until the next month.
+        if let Some(usage) = spartan_camera
+            .subscription
+            .as_ref()
+            .and_then(|s| QuotaUsage::from_subscription(s, DateTime::now()))
+        {
+            sync_result.photo_count = usage.used;
+            sync_result.photo_quota = usage.quota;
+            sync_result.projected_photos = usage.projected;
+
+            // A change already sent this month isn't escalated until the camera applied it.
+            if let Some(update) = quota::recommend(
+                &camera.config,
+                &usage,
+                spartan_camera.quota_adjustment.as_ref(),
+                DateTime::now(),
+            ) {
+                warn!(
+                    "sync::main camera {} projected {} of {} monthly photos, recommended {:?}",
+                    camera.config.name, usage.projected, usage.quota, update
+                );
+
+                if config.quota_adjust {
+                    match spypoint::update_config(&client, &camera.id, &update).await {
+                        Ok(()) => {
+                            sync_result.config_updated = true;
+
+                            let adjustment =
+                                QuotaAdjustment::new(update.clone(), &usage, DateTime::now());
+                            if let Err(e) =
+                                spartan_camera.save_quota_adjustment(&db, adjustment).await
+                            {
+                                error!(
+                                    "sync::main saving quota adjustment, {}...{:?}",
+                                    camera.clone().config.name,
+                                    e
+                                );
+                                err_counter += 1;
+                            }
+                        }
+                        Err(e) => {
+                            let msg = format!(
+                                "sync::main updating camera config, {}...{:?}",
+                                camera.clone().config.name,
+                                e
+                            );
+                            error!("{}", msg);
+                            // send msg to Slack
+                            let _ = slack::save_error(
+                                client.http_client(),
+                                config.slack_url.clone(),
+                                msg,
+                                String::from("Sync.rs"),
+                            )
+                            .await;
+
+                            err_counter += 1;
+                        }
+                    }
+                }
+
+                sync_result.recommended_config = Some(update);
             }
         }
 
@@ -241,7 +573,7 @@ async fn main() {
             };
 
         for photo in photo_response.photos {
-            let mut picture = Picture::from(photo);
+            let mut picture = Picture::from_photo(photo, config.photo_quality);
 
             // check if pic exists and date
             if !picture.within_days(config.sync_days as i64) {
@@ -253,16 +585,36 @@ async fn main() {
                 continue;
             }
 
-            // check DB to see if pic exists.
-            if let Ok(x) = picture.exists(&db).await {
-                if x {
-                    info!(
-                        "sync.rs::main picture exists in db, Id: {}, Date: {}",
-                        picture.photo_id, picture.picture_date
-                    );
-                    sync_result.skipped += 1;
-                    continue;
+            // check DB to see if pic exists, pictures stored in a smaller quality are upgraded
+            // when the rendition wanted is available.
+            if let Ok(Some(mut stored)) = Picture::find_by_photo_id(&db, &picture.photo_id).await {
+                if stored.quality < picture.quality {
+                    match stored
+                        .upgrade(
+                            &db,
+                            &client.http_client(),
+                            &gcp_client,
+                            picture.sources.clone(),
+                            picture.quality,
+                            &config.upload_options,
+                        )
+                        .await
+                    {
+                        Ok(true) => sync_result.upgraded += 1,
+                        Ok(false) => {}
+                        Err(e) => warn!(
+                            "sync.rs::main upgrading picture id: {}...{:?}",
+                            picture.photo_id, e
+                        ),
+                    }
                 }
+
+                info!(
+                    "sync.rs::main picture exists in db, Id: {}, Date: {}",
+                    picture.photo_id, picture.picture_date
+                );
+                sync_result.skipped += 1;
+                continue;
             }
 
             debug!(
@@ -273,15 +625,18 @@ async fn main() {
             // Set fields
             picture.account_id = spartan_camera.clone().account_id;
 
-            // Download Pic, Save to Cloud Storage, Gen Thumbnail, Save thumb to Cloud storage
-            // and save Pic to db.
+            // Download Pic, compute the sun and moon phase and look up the weather for its EXIF
+            // date, Save to Cloud Storage, Gen Thumbnail, Save thumb to Cloud storage and save
+            // Pic to db.
             if let Err(e) = picture
                 .upload(
                     &db,
                     &client.http_client(),
-                    spartan_camera.clone().name,
+                    &spartan_camera,
                     &gcp_client,
                     config.gcp_bucket.clone(),
+                    weather.as_ref(),
+                    &config.upload_options,
                 )
                 .await
             {
@@ -293,6 +648,19 @@ async fn main() {
                 );
 
                 error!("{}", msg);
+
+                // Failed uploads are recorded, downloads that weren't a whole JPEG under their
+                // own stage.
+                let stage = if e.is::<InvalidImage>() {
+                    "validate"
+                } else {
+                    "upload"
+                };
+                let sync_error = SyncError::new(stage, &spartan_camera, &picture, e.to_string());
+                if let Err(e) = sync_error.save(&db).await {
+                    error!("sync.rs::main saving sync error...{:?}", e);
+                }
+
                 // send msg to Slack
                 let _ = slack::save_error(
                     client.http_client(),
@@ -309,16 +677,47 @@ async fn main() {
 
             // Sleep Thread?
             // tokio::time::sleep(Duration::new(2, 0)).await;
+            if picture.likely_empty {
+                sync_result.empty += 1;
+            }
+
+            // A skipped empty frame is only recorded, nothing was uploaded.
+            if picture.thumb_path.is_empty() {
+                info!("sync.rs::main picture id: {} skipped empty...", picture.photo_id);
+                continue;
+            }
             info!("sync.rs::main picture id: {} uploaded...", picture.photo_id);
             sync_result.uploaded += 1;
         }
 
+        // Stored photos, counted again with the pictures of this run.
+        match Picture::count(&db, &spartan_camera.camera_id).await {
+            Ok(x) => {
+                if let Err(e) = spartan_camera.save_stored_photos(&db, x).await {
+                    warn!(
+                        "sync::main error saving stored pictures, {}...{:?}",
+                        camera.clone().config.name,
+                        e
+                    );
+                }
+            }
+            Err(e) => warn!(
+                "sync::main error counting stored pictures, {}...{:?}",
+                camera.clone().config.name,
+                e
+            ),
+        }
+
         info!(
-            "sync::main processing camera, {}, skipped: {}, uploaded: {}, errors: {}, complete",
+            "sync::main processing camera, {}, skipped: {}, uploaded: {}, empty: {}, upgraded: {}, errors: {}, projected photos: {}/{}, complete",
             camera.clone().config.name,
             sync_result.skipped,
             sync_result.uploaded,
+            sync_result.empty,
+            sync_result.upgraded,
             sync_result.errors,
+            sync_result.projected_photos,
+            sync_result.photo_quota,
         );
 
         // Save Sync Metrics for Camera.
@@ -358,6 +757,12 @@ pub struct Config {
     gcp_bucket: String,
     sync_days: u64,
     slack_url: String,
+    upload_options: UploadOptions,
+    alert_thresholds: Thresholds,
+    quota_adjust: bool,
+    photo_quality: Quality,
+    /// Zip code centroids the zip of cameras is set from, None when not configured.
+    zip_centroids: Option<ZipCentroids>,
 }
 
 impl Config {
@@ -373,6 +778,31 @@ impl Config {
         let slack_url = env::var("SLACK_URL").expect("SLACK_URL");
         let gcp_bucket = env::var("GOOGLE_CLOUD_BUCKET").expect("GOOGLE_CLOUD_BUCKET");
         let sync_days = env::var("SYNC_DAYS").unwrap_or(String::from("2"));
+
+        let mut upload_options = UploadOptions {
+            read_banner: env_bool("BANNER_READ"),
+            strip_banner: env_bool("BANNER_STRIP"),
+            enhance: EnhanceOptions {
+                orient: env_bool("THUMB_ORIENT"),
+                auto_levels: env_bool("THUMB_AUTO_LEVELS"),
+                sharpen: env_bool("THUMB_SHARPEN"),
+            },
+            detector: load_detector(),
+            empty_frames: EmptyFrames::from(env::var("EMPTY_FRAMES").unwrap_or_default().as_str()),
+            ..Default::default()
+        };
+        if let Ok(x) = env::var("EMPTY_MAX_CHANGED") {
+            upload_options.empty_max_changed = x
+                .parse::<f32>()
+                .unwrap_or(upload_options.empty_max_changed);
+        }
+        if let Ok(x) = env::var("BANNER_RATIO") {
+            upload_options.banner_ratio = x.parse::<f32>().unwrap_or(upload_options.banner_ratio);
+        }
+        if let Ok(x) = env::var("STORAGE_PATH_TEMPLATE") {
+            upload_options.path_template = PathTemplate::new(&x).expect("STORAGE_PATH_TEMPLATE");
+        }
+
         Config {
             spypoint_user: sp_user,
             spypoint_pwd: sp_pwd,
@@ -380,6 +810,40 @@ impl Config {
             gcp_bucket,
             sync_days: sync_days.parse::<u64>().unwrap_or(DAYS_OF_PICS),
             slack_url,
+            upload_options,
+            alert_thresholds: Thresholds::from_env(),
+            quota_adjust: env_bool("QUOTA_ADJUST"),
+            photo_quality: Quality::from(env::var("PHOTO_QUALITY").unwrap_or_default().as_str()),
+            zip_centroids: ZipCentroids::from_env(),
+        }
+    }
+}
+
+/// Loads the detection model from the environment, None when DETECT_MODEL isn't set or the
+/// model can't be loaded.
+#[cfg(feature = "detect")]
+fn load_detector() -> Option<Arc<dyn Detector>> {
+    match OnnxDetector::from_env()? {
+        Ok(d) => {
+            info!("sync::load_detector loaded {:?}", d);
+            Some(Arc::new(d))
         }
+        Err(e) => {
+            error!("sync::load_detector unable to load the model, {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "detect"))]
+fn load_detector() -> Option<Arc<dyn Detector>> {
+    None
+}
+
+/// Reads a boolean flag from the environment, missing vars are false.
+fn env_bool(key: &str) -> bool {
+    match env::var(key) {
+        Ok(x) => x.to_lowercase() == "true",
+        Err(_) => false,
     }
 }